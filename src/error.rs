@@ -1,6 +1,8 @@
 use std::error::Error as E;
 use std::fmt;
+use std::io;
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub enum ErrorKind {
     OsError,
@@ -8,6 +10,10 @@ pub enum ErrorKind {
     ExecError,
     UnicodeDecodeError,
     InvalidFormatError,
+    TimeoutError,
+    CommandNotFound,
+    ParseError,
+    Cancelled,
 }
 
 #[derive(Debug)]
@@ -15,6 +21,11 @@ pub struct Error {
     kind: ErrorKind,
     code: Option<i32>,
     details: String,
+    partial_output: Option<String>,
+    stderr: Option<String>,
+    // Boxed to keep Error under clippy's large error size.
+    valid_prefix: Option<Box<str>>,
+    source: Option<Box<dyn E + Send + Sync>>,
 }
 
 impl Error {
@@ -23,15 +34,122 @@ impl Error {
             kind,
             code,
             details: msg.to_string(),
+            partial_output: None,
+            stderr: None,
+            valid_prefix: None,
+            source: None,
         }
     }
 
     pub fn kind(&self) -> ErrorKind {
-        return self.kind;
+        self.kind
     }
 
     pub fn code(&self) -> Option<i32> {
-        return self.code;
+        self.code
+    }
+
+    /// Output produced by the earlier stages of a pipeline before a later
+    /// stage failed to spawn, if any.
+    pub fn partial_output(&self) -> Option<&str> {
+        self.partial_output.as_deref()
+    }
+
+    pub(crate) fn with_partial_output(mut self, output: String) -> Error {
+        self.partial_output = Some(output);
+        self
+    }
+
+    /// The stderr captured from the pipeline, if any.
+    pub fn stderr(&self) -> Option<&str> {
+        self.stderr.as_deref()
+    }
+
+    /// Attach captured stderr to the error, to be shown by `report()`.
+    pub fn with_stderr(mut self, stderr: String) -> Error {
+        self.stderr = Some(stderr);
+        self
+    }
+
+    /// For a UnicodeDecodeError, the output up to the first byte that is not
+    /// valid utf-8, so the readable beginning of the output can be salvaged.
+    pub fn valid_prefix(&self) -> Option<&str> {
+        self.valid_prefix.as_deref()
+    }
+
+    pub(crate) fn with_valid_prefix(mut self, prefix: String) -> Error {
+        self.valid_prefix = Some(prefix.into_boxed_str());
+        self
+    }
+
+    pub(crate) fn with_source(mut self, source: impl E + Send + Sync + 'static) -> Error {
+        self.source = Some(Box::new(source));
+        self
+    }
+
+    /// The signal that likely killed the command, following the shell
+    /// convention that an exit code of 128+N means death by signal N. Returns
+    /// `code - 128` when the code is above 128 and at most 165, and None
+    /// otherwise. This is a heuristic for when the signal itself is not known,
+    /// since a command can also exit with such a code on its own.
+    pub fn signal_from_code(&self) -> Option<i32> {
+        match self.code {
+            Some(code) if code > 128 && code <= 165 => Some(code - 128),
+            _ => None,
+        }
+    }
+
+    /// Render the error as a multi-line report for display to a user,
+    /// including the kind, code, details, any captured stderr or partial
+    /// output, and the chain of underlying causes.
+    pub fn report(&self) -> String {
+        let mut report = format!("error: {}\n", self.details);
+        report.push_str(&format!("  kind: {:?}\n", self.kind));
+        if let Some(code) = self.code {
+            report.push_str(&format!("  code: {}\n", code));
+        }
+        for (name, text) in [
+            ("stderr", &self.stderr),
+            ("partial output", &self.partial_output),
+        ] {
+            if let Some(text) = text {
+                report.push_str(&format!("  {}:\n", name));
+                for line in text.lines() {
+                    report.push_str(&format!("    {}\n", line));
+                }
+            }
+        }
+        let mut source = E::source(self);
+        while let Some(cause) = source {
+            report.push_str(&format!("  caused by: {}\n", cause));
+            source = cause.source();
+        }
+        report
+    }
+
+    /// Convert the error into an `anyhow::Error`, with the kind and code
+    /// attached as context. The error itself stays in the chain, so it can be
+    /// recovered with `downcast_ref()`, and its source chain is shown by the
+    /// alternate `{:#}` format along with the context.
+    ///
+    /// This is only available with the `anyhow` feature.
+    #[cfg(feature = "anyhow")]
+    pub fn into_anyhow(self) -> anyhow::Error {
+        let context = match self.code {
+            Some(code) => format!("{:?} (code {})", self.kind, code),
+            None => format!("{:?}", self.kind),
+        };
+        anyhow::Error::new(self).context(context)
+    }
+
+    // Map an io::Error to OsError if it carries a raw os error, or UnknownError
+    // otherwise, keeping it as the source.
+    pub(crate) fn from_io(e: io::Error) -> Error {
+        let err = match e.raw_os_error() {
+            Some(raw_os_err) => Error::new(ErrorKind::OsError, Some(raw_os_err), &e.to_string()),
+            None => Error::new(ErrorKind::UnknownError, None, &e.to_string()),
+        };
+        err.with_source(e)
     }
 }
 
@@ -45,4 +163,71 @@ impl E for Error {
     fn description(&self) -> &str {
         &self.details
     }
+
+    fn source(&self) -> Option<&(dyn E + 'static)> {
+        self.source.as_deref().map(|e| e as &(dyn E + 'static))
+    }
+}
+
+// Tests
+//_____________________________________________________________________________
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report() {
+        let err = Error::new(ErrorKind::ExecError, Some(2), "non-zero exit code")
+            .with_stderr("ls: cannot access 'x'\nsecond line\n".to_string())
+            .with_source(io::Error::other("underlying cause"));
+
+        assert_eq!(
+            err.report(),
+            "error: non-zero exit code\n\
+             \x20 kind: ExecError\n\
+             \x20 code: 2\n\
+             \x20 stderr:\n\
+             \x20   ls: cannot access 'x'\n\
+             \x20   second line\n\
+             \x20 caused by: underlying cause\n"
+        );
+    }
+
+    #[test]
+    fn test_signal_from_code() {
+        let signal = |code| Error::new(ErrorKind::ExecError, code, "").signal_from_code();
+        assert_eq!(signal(Some(137)), Some(9));
+        assert_eq!(signal(Some(130)), Some(2));
+        assert_eq!(signal(Some(165)), Some(37));
+        assert_eq!(signal(Some(1)), None);
+        assert_eq!(signal(Some(128)), None);
+        assert_eq!(signal(Some(166)), None);
+        assert_eq!(signal(None), None);
+    }
+
+    #[cfg(feature = "anyhow")]
+    #[test]
+    fn test_into_anyhow() {
+        let err = Error::new(ErrorKind::ExecError, Some(2), "non-zero exit code")
+            .with_source(io::Error::other("underlying cause"))
+            .into_anyhow();
+        assert_eq!(err.to_string(), "ExecError (code 2)");
+        assert_eq!(
+            format!("{:#}", err),
+            "ExecError (code 2): non-zero exit code: underlying cause"
+        );
+        let inner = err.downcast_ref::<Error>().unwrap();
+        assert_eq!(inner.kind(), ErrorKind::ExecError);
+        assert_eq!(err.chain().count(), 3);
+    }
+
+    #[test]
+    fn test_report_minimal() {
+        let err = Error::new(ErrorKind::TimeoutError, None, "deadline exceeded");
+        assert_eq!(
+            err.report(),
+            "error: deadline exceeded\n  kind: TimeoutError\n"
+        );
+    }
 }
@@ -1,7 +1,35 @@
-use std::process::{Command, Stdio};
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::process::{ChildStdin, ExitStatus};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
 mod error;
-use crate::error::{Error, ErrorKind};
+mod pipeline;
+
+use crate::pipeline::{
+    check_status, decode_utf8, exit_code, find_in_path, find_in_path_cached, shell_escape,
+};
+
+pub use crate::error::{Error, ErrorKind};
+pub use crate::pipeline::{
+    ExecResult, IoConfig, Pipeline, PipelineHandle, PipelineOutcome, PipelineReader, StderrMode,
+    StdinMode, StdoutMode,
+};
+
+#[cfg(target_os = "linux")]
+pub use crate::pipeline::{IoClass, RUsage};
+
+#[cfg(feature = "encoding")]
+pub use encoding_rs;
+#[cfg(feature = "encoding")]
+use encoding_rs::Encoding;
 
 // pipe()
 //_____________________________________________________________________________
@@ -10,7 +38,7 @@ use crate::error::{Error, ErrorKind};
 /// shell commands, where the output of the first command is connected via a
 /// pipe to the input of the second. The stdout of the final command in the
 /// pipeline is returned as a String, wrapped in a Result.
-
+///
 /// In the case of non-zero exit status, return a shutil::Error result
 /// with kind() set to ExecError and code() set to the exit code.
 ///
@@ -18,194 +46,2511 @@ use crate::error::{Error, ErrorKind};
 /// shutil::Error with kind() set to OsError and code() set to the raw os error.
 ///
 /// If the command succeeds, but stdout is not valid utf-8, it will return a
-/// shutil::Error with kind() set to UnicodeDecodeError.
+/// shutil::Error with kind() set to UnicodeDecodeError, and valid_prefix() set
+/// to the output before the first invalid byte.
 ///
 /// In other error cases, it will return a shutil::Error with kind() set to
 /// UnknownError.
-
 pub fn pipe(commands: Vec<Vec<&str>>) -> Result<String, Error> {
-    if commands.len() < 1 {
-        return Err(Error::new(
-            ErrorKind::InvalidFormatError,
-            Some(-1),
-            "no commands supplied",
-        ));
+    Pipeline::new(commands).output()
+}
+
+// pipe_diff()
+//_____________________________________________________________________________
+
+/// Execute two shell command pipelines and compare their stdout. Returns
+/// `Ok(None)` if the output is identical, or `Ok(Some(diff))` where `diff` is
+/// a unified-diff-style comparison of the two outputs, line by line. A final
+/// line without a trailing newline differs from the same line with one, and
+/// is followed by a `\ No newline at end of file` marker, as with `diff -u`.
+///
+/// If either pipeline fails, the error is returned as with `pipe()`.
+pub fn pipe_diff(a: Vec<Vec<&str>>, b: Vec<Vec<&str>>) -> Result<Option<String>, Error> {
+    let a_output = pipe(a)?;
+    let b_output = pipe(b)?;
+
+    if a_output == b_output {
+        return Ok(None);
     }
 
-    let mut last_command: Option<Command> = None;
+    // Lines keep their newline, so a missing one at the end is a difference.
+    let a_lines: Vec<&str> = a_output.split_inclusive('\n').collect();
+    let b_lines: Vec<&str> = b_output.split_inclusive('\n').collect();
 
-    for i in 0..commands.len() {
-        let command_str = &commands[i];
+    // Longest common subsequence table, where lcs[i][j] is the length of the
+    // LCS of a_lines[i..] and b_lines[j..].
+    let mut lcs = vec![vec![0usize; b_lines.len() + 1]; a_lines.len() + 1];
+    for i in (0..a_lines.len()).rev() {
+        for j in (0..b_lines.len()).rev() {
+            lcs[i][j] = if a_lines[i] == b_lines[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
 
-        if command_str.len() == 0 {
-            return Err(Error::new(
-                ErrorKind::InvalidFormatError,
-                Some(-1),
-                "no command binary supplied",
-            ));
+    // An empty range starts at line 0 in a unified diff hunk header.
+    let start = |lines: &[&str]| usize::from(!lines.is_empty());
+    let mut diff = format!(
+        "--- a\n+++ b\n@@ -{},{} +{},{} @@\n",
+        start(&a_lines),
+        a_lines.len(),
+        start(&b_lines),
+        b_lines.len()
+    );
+    let mut push_line = |prefix: char, line: &str| {
+        diff.push(prefix);
+        match line.strip_suffix('\n') {
+            Some(line) => {
+                diff.push_str(line);
+                diff.push('\n');
+            }
+            None => {
+                diff.push_str(line);
+                diff.push_str("\n\\ No newline at end of file\n");
+            }
+        }
+    };
+    let (mut i, mut j) = (0, 0);
+    while i < a_lines.len() || j < b_lines.len() {
+        if i < a_lines.len() && j < b_lines.len() && a_lines[i] == b_lines[j] {
+            push_line(' ', a_lines[i]);
+            i += 1;
+            j += 1;
+        } else if i < a_lines.len() && (j == b_lines.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            push_line('-', a_lines[i]);
+            i += 1;
+        } else {
+            push_line('+', b_lines[j]);
+            j += 1;
         }
+    }
 
-        // Parse binary
-        let mut command = Command::new(command_str[0]);
+    Ok(Some(diff))
+}
 
-        // Parse args
-        for j in 1..command_str.len() {
-            command.arg(command_str[j]);
-        }
+// pipe_latency()
+//_____________________________________________________________________________
 
-        // Set stdout
-        command.stdout(Stdio::piped());
+/// Timing information recorded by `pipe_latency()`. All durations are
+/// measured from the start of the call.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct LatencyStats {
+    /// Time taken to spawn every command in the pipeline.
+    pub spawn: Duration,
+    /// Time until the first byte of stdout was read, or None if the pipeline
+    /// produced no output.
+    pub first_byte: Option<Duration>,
+    /// Time until the pipeline exited.
+    pub total: Duration,
+}
 
-        // Spawn previous command in the chain and set it as stdin for the next command
-        if let Some(mut prev) = last_command {
-            match prev.spawn() {
-                Ok(r) => {
-                    if let Some(stdout) = r.stdout {
-                        command.stdin(stdout);
-                    }
+/// Execute a shell command pipeline and return stdout along with timing
+/// information, to distinguish startup latency from processing latency.
+///
+/// Errors are returned as with `pipe()`.
+pub fn pipe_latency(commands: Vec<Vec<&str>>) -> Result<(String, LatencyStats), Error> {
+    let start = Instant::now();
+    let mut spawned = Pipeline::new(commands).start()?;
+    let spawn = start.elapsed();
+
+    let mut stdout = Vec::new();
+    let mut first_byte = None;
+    if let Some(mut reader) = spawned.stdout.take() {
+        let mut buf = [0u8; 8192];
+        loop {
+            match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => {
+                    first_byte.get_or_insert_with(|| start.elapsed());
+                    stdout.extend_from_slice(&buf[..n]);
                 }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
                 Err(e) => {
-                    return Err(Error::new(
-                        ErrorKind::ExecError,
-                        Some(-1),
-                        format!("spawning failed: {}", e.to_string()).as_str(),
-                    ));
+                    spawned.kill();
+                    return Err(Error::from_io(e));
                 }
             }
         }
-
-        last_command = Some(command);
     }
 
-    // Execute the last command in the chain and return the utf-8 decoded output
-    match last_command {
-        None => {
-            return Err(Error::new(
-                ErrorKind::InvalidFormatError,
-                Some(-1),
-                "no commands supplied",
-            ));
-        }
-        Some(mut cmd) => match cmd.output() {
-            Ok(result) => {
-                if !result.status.success() {
-                    return Err(Error::new(
-                        ErrorKind::ExecError,
-                        result.status.code(),
-                        "non-zero exit code",
-                    ));
+    let status = spawned.wait()?;
+    let total = start.elapsed();
+    check_status(status)?;
+
+    let output = decode_utf8(stdout)?;
+    Ok((
+        output,
+        LatencyStats {
+            spawn,
+            first_byte,
+            total,
+        },
+    ))
+}
+
+// pipe_until()
+//_____________________________________________________________________________
+
+/// Execute a shell command pipeline, reading stdout line by line until a line
+/// satisfies `pred`. Once a line matches, the pipeline is killed and the output
+/// collected so far, including the matching line, is returned. This is useful
+/// for waiting on a startup signal such as a server logging "Ready".
+///
+/// If no line matches, the pipeline runs to completion and errors are returned
+/// as with `pipe()`.
+pub fn pipe_until<F: FnMut(&str) -> bool>(
+    commands: Vec<Vec<&str>>,
+    mut pred: F,
+) -> Result<String, Error> {
+    let mut spawned = Pipeline::new(commands).start()?;
+    let mut output = String::new();
+
+    if let Some(stdout) = spawned.stdout.take() {
+        let mut reader = BufReader::new(stdout);
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            match reader.read_until(b'\n', &mut line) {
+                Ok(0) => break,
+                Ok(_) => {}
+                Err(e) => {
+                    spawned.kill();
+                    return Err(Error::from_io(e));
                 }
-                match String::from_utf8(result.stdout) {
-                    Ok(v) => Ok(v),
-                    Err(_e) => Err(Error::new(
+            }
+
+            let line = match std::str::from_utf8(&line) {
+                Ok(v) => v,
+                Err(_e) => {
+                    spawned.kill();
+                    return Err(Error::new(
                         ErrorKind::UnicodeDecodeError,
                         None,
                         "utf-8 decode failed",
-                    )),
+                    ));
                 }
+            };
+            output.push_str(line);
+
+            if pred(line.trim_end_matches('\n')) {
+                spawned.kill();
+                return Ok(output);
             }
-            Err(e) => {
-                if let Some(raw_os_err) = e.raw_os_error() {
-                    return Err(Error::new(
-                        ErrorKind::OsError,
-                        Some(raw_os_err),
-                        &e.to_string(),
-                    ));
-                } else {
-                    return Err(Error::new(ErrorKind::UnknownError, None, &e.to_string()));
+        }
+    }
+
+    check_status(spawned.wait()?)?;
+    Ok(output)
+}
+
+// pipe_lossy_flagged()
+//_____________________________________________________________________________
+
+/// Execute a shell command pipeline and return stdout decoded lossily, along
+/// with a flag that is true if any invalid utf-8 was replaced with U+FFFD.
+/// This decodes permissively while still letting the caller detect encoding
+/// problems.
+///
+/// Errors other than UnicodeDecodeError are returned as with `pipe()`.
+pub fn pipe_lossy_flagged(commands: Vec<Vec<&str>>) -> Result<(String, bool), Error> {
+    let stdout = Pipeline::new(commands).output_bytes()?;
+    match String::from_utf8_lossy(&stdout) {
+        Cow::Borrowed(v) => Ok((v.to_string(), false)),
+        Cow::Owned(v) => Ok((v, true)),
+    }
+}
+
+// pipe_supervise()
+//_____________________________________________________________________________
+
+/// Handle to a supervisor started by `pipe_supervise()`.
+///
+/// Dropping the handle stops the supervisor as `stop()` does, ignoring any
+/// error.
+#[must_use = "dropping the handle stops the supervisor"]
+pub struct SupervisorHandle {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<Result<(), Error>>>,
+}
+
+impl SupervisorHandle {
+    /// Stop the supervisor, killing the pipeline if it is running, and wait
+    /// for the supervisor thread to exit. Returns the error that ended the
+    /// supervisor if it had already failed, as with `wait()`.
+    pub fn stop(mut self) -> Result<(), Error> {
+        self.stop.store(true, Ordering::SeqCst);
+        self.join()
+    }
+
+    /// Wait for the supervisor to exit on its own, which happens once
+    /// `on_exit` returns false or the pipeline can no longer be spawned or
+    /// waited on. In the latter case the error is returned as with `pipe()`.
+    pub fn wait(mut self) -> Result<(), Error> {
+        self.join()
+    }
+
+    fn join(&mut self) -> Result<(), Error> {
+        match self.thread.take().map(|thread| thread.join()) {
+            Some(Ok(result)) => result,
+            Some(Err(_e)) => Err(Error::new(
+                ErrorKind::UnknownError,
+                None,
+                "supervisor panicked",
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for SupervisorHandle {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        let _ = self.join();
+    }
+}
+
+/// Execute a shell command pipeline under a supervisor thread that respawns it
+/// whenever it exits. After each exit, `on_exit` is called with the exit code
+/// of the final command (None if it was killed by a signal), and the pipeline
+/// is restarted while it returns true. The stdout of the pipeline is
+/// discarded.
+///
+/// Errors spawning the pipeline the first time are returned as with `pipe()`.
+/// If a later respawn or waiting for the pipeline fails, the supervisor kills
+/// the pipeline and exits, and the error is returned from
+/// `SupervisorHandle::wait()` or `SupervisorHandle::stop()`.
+pub fn pipe_supervise<F>(
+    commands: Vec<Vec<&str>>,
+    mut on_exit: F,
+) -> Result<SupervisorHandle, Error>
+where
+    F: FnMut(Option<i32>) -> bool + Send + 'static,
+{
+    let mut pipeline = Pipeline::new(commands).discard_stdout();
+    let mut spawned = pipeline.start()?;
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let stop_flag = Arc::clone(&stop);
+    let thread = thread::spawn(move || loop {
+        let code = loop {
+            if stop_flag.load(Ordering::SeqCst) {
+                spawned.kill();
+                return Ok(());
+            }
+            match spawned.try_wait() {
+                Ok(Some(status)) => break status.code(),
+                Ok(None) => thread::sleep(Duration::from_millis(10)),
+                Err(e) => {
+                    spawned.kill();
+                    return Err(e);
                 }
             }
-        },
+        };
+
+        if !on_exit(code) || stop_flag.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        spawned = pipeline.start()?;
+    });
+
+    Ok(SupervisorHandle {
+        stop,
+        thread: Some(thread),
+    })
+}
+
+// pipe_byte_count()
+//_____________________________________________________________________________
+
+/// Execute a shell command pipeline and return the number of bytes written to
+/// stdout. The output is counted as it is read rather than buffered, so this
+/// measures large outputs without holding them in memory, like `| wc -c`.
+///
+/// Errors are returned as with `pipe()`.
+pub fn pipe_byte_count(commands: Vec<Vec<&str>>) -> Result<usize, Error> {
+    let mut spawned = Pipeline::new(commands).start()?;
+    let mut count = 0;
+    if let Some(mut reader) = spawned.stdout.take() {
+        match io::copy(&mut reader, &mut io::sink()) {
+            Ok(n) => count = n as usize,
+            Err(e) => {
+                spawned.kill();
+                return Err(Error::from_io(e));
+            }
+        }
     }
+    check_status(spawned.wait()?)?;
+    Ok(count)
 }
 
-// Tests
+// poll_until_changed()
 //_____________________________________________________________________________
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// Execute a shell command pipeline, then re-run it every `interval` until its
+/// stdout differs from the first run, and return the changed output. This
+/// supports waiting on external state transitions, such as a status command
+/// reporting ready.
+///
+/// If the output has not changed after `max` further runs, a shutil::Error
+/// with kind() set to TimeoutError is returned. Errors from any run are
+/// returned as with `pipe()`.
+pub fn poll_until_changed(
+    commands: Vec<Vec<&str>>,
+    interval: Duration,
+    max: u32,
+) -> Result<String, Error> {
+    let mut pipeline = Pipeline::new(commands);
+    let first = pipeline.output_bytes()?;
 
-    // Zero command tests
+    for _ in 0..max {
+        thread::sleep(interval);
+        let output = pipeline.output_bytes()?;
+        if output != first {
+            return decode_utf8(output);
+        }
+    }
 
-    #[test]
-    fn test_no_commands() {
-        let output = pipe(vec![]);
+    Err(Error::new(
+        ErrorKind::TimeoutError,
+        None,
+        format!("output unchanged after {} attempts", max).as_str(),
+    ))
+}
 
-        assert_eq!(
-            output.as_ref().unwrap_err().kind(),
-            ErrorKind::InvalidFormatError
-        );
-        assert_eq!(output.as_ref().unwrap_err().code(), Some(-1));
+// pipe_reencode()
+//_____________________________________________________________________________
+
+/// Execute a shell command pipeline, decode its stdout from the `from`
+/// encoding and re-encode it to the `to` encoding, returning the bytes. This
+/// normalizes legacy output to utf-8, or converts utf-8 output for systems
+/// that expect another encoding.
+///
+/// If stdout is not valid in the `from` encoding, or contains characters that
+/// cannot be represented in the `to` encoding, a shutil::Error with kind() set
+/// to UnicodeDecodeError is returned. Other errors are returned as with
+/// `pipe()`.
+///
+/// Requires the `encoding` feature. Note that `encoding_rs` cannot encode to
+/// UTF-16, and encodes to utf-8 if `to` is a UTF-16 encoding.
+#[cfg(feature = "encoding")]
+pub fn pipe_reencode(
+    from: &'static Encoding,
+    to: &'static Encoding,
+    commands: Vec<Vec<&str>>,
+) -> Result<Vec<u8>, Error> {
+    let stdout = Pipeline::new(commands).output_bytes()?;
+
+    let decoded = match from.decode_without_bom_handling_and_without_replacement(&stdout) {
+        Some(v) => v,
+        None => {
+            return Err(Error::new(
+                ErrorKind::UnicodeDecodeError,
+                None,
+                format!("{} decode failed", from.name()).as_str(),
+            ));
+        }
+    };
+
+    let (encoded, _encoding, unmappable) = to.encode(&decoded);
+    if unmappable {
+        return Err(Error::new(
+            ErrorKind::UnicodeDecodeError,
+            None,
+            format!("{} encode failed", to.name()).as_str(),
+        ));
     }
+    Ok(encoded.into_owned())
+}
 
-    #[test]
-    fn test_no_binary() {
-        let output = pipe(vec![vec![]]);
+// pipe_code()
+//_____________________________________________________________________________
 
-        assert_eq!(
-            output.as_ref().unwrap_err().kind(),
-            ErrorKind::InvalidFormatError
-        );
-        assert_eq!(output.as_ref().unwrap_err().code(), Some(-1));
+/// Execute a shell command pipeline with stdout discarded and return the exit
+/// code of the final command. A non-zero exit code is returned as Ok rather
+/// than treated as an error.
+///
+/// If the final command is killed by a signal, it has no exit code and a
+/// shutil::Error with kind() set to ExecError and code() set to None is
+/// returned. OS errors such as command not found are returned as with
+/// `pipe()`.
+pub fn pipe_code(commands: Vec<Vec<&str>>) -> Result<i32, Error> {
+    exit_code(run_discarding(commands)?)
+}
+
+// pipe_then()
+//_____________________________________________________________________________
+
+/// Execute a shell command pipeline and apply `f` to the stdout, returning the
+/// result. For example, `pipe_then(commands, |s| s.lines().count())` counts
+/// the lines of output.
+///
+/// Errors are returned as with `pipe()`, in which case `f` is not called.
+pub fn pipe_then<T>(commands: Vec<Vec<&str>>, f: impl FnOnce(String) -> T) -> Result<T, Error> {
+    pipe(commands).map(f)
+}
+
+// sh()
+//_____________________________________________________________________________
+
+/// The shell used by `sh()` when the SHUTIL_SHELL environment variable is not
+/// set.
+const DEFAULT_SHELL: &str = "/bin/sh";
+
+/// Execute `script` with `/bin/sh -c` and return stdout. This is convenient for
+/// scripts that use shell syntax, such as redirection, which `pipe()` does not
+/// interpret. The shell can be overridden by setting the SHUTIL_SHELL
+/// environment variable to a path, or to a name that is looked up on PATH.
+///
+/// If the shell does not exist, it will return a shutil::Error with kind() set
+/// to CommandNotFound and a message naming the shell. Other errors are
+/// returned as with `pipe()`.
+pub fn sh(script: &str) -> Result<String, Error> {
+    let shell = env::var("SHUTIL_SHELL").unwrap_or_else(|_| DEFAULT_SHELL.to_string());
+    sh_with(&shell, script)
+}
+
+// Execute `script` with `shell -c`, as sh() does once it has chosen the shell.
+fn sh_with(shell: &str, script: &str) -> Result<String, Error> {
+    let found = if shell.contains('/') {
+        Path::new(shell).is_file()
+    } else {
+        find_in_path(shell).is_some()
+    };
+    if !found {
+        return Err(Error::new(
+            ErrorKind::CommandNotFound,
+            None,
+            format!("shell not found: {}", shell).as_str(),
+        ));
     }
 
-    // Single command tests
+    pipe(vec![vec![shell, "-c", script]])
+}
 
-    #[test]
-    fn test_single_not_found() {
-        let output = pipe(vec![vec!["/does/not/exist"]]);
+// pipe_with_stderr_callback()
+//_____________________________________________________________________________
 
-        assert_eq!(output.as_ref().unwrap_err().kind(), ErrorKind::OsError);
+/// Execute a shell command pipeline, calling `on_stderr` with each line written
+/// to stderr by any stage as it arrives, and return the stdout along with the
+/// exit code of the final command. Both streams are read concurrently, so a
+/// command writing heavily to either one cannot deadlock the pipeline. Lines
+/// are passed without the trailing newline, and invalid utf-8 in stderr is
+/// replaced with U+FFFD.
+///
+/// A non-zero exit code is returned as Ok. If the final command is killed by a
+/// signal, a shutil::Error with kind() set to ExecError and code() set to None
+/// is returned. Other errors are returned as with `pipe()`.
+pub fn pipe_with_stderr_callback<F: FnMut(&str)>(
+    commands: Vec<Vec<&str>>,
+    mut on_stderr: F,
+) -> Result<(String, i32), Error> {
+    let mut spawned = Pipeline::new(commands).pipe_stderr().start()?;
 
-        // errno 2 is ENOENT No such file or directory.
-        assert_eq!(output.as_ref().unwrap_err().code(), Some(2));
+    let (tx, rx) = mpsc::channel();
+    for stderr in spawned.stderr.drain(..) {
+        let tx = tx.clone();
+        thread::spawn(move || {
+            for line in BufReader::new(stderr).split(b'\n') {
+                match line {
+                    Ok(line) => {
+                        let line = String::from_utf8_lossy(&line).into_owned();
+                        if tx.send(line).is_err() {
+                            break;
+                        }
+                    }
+                    Err(_e) => break,
+                }
+            }
+        });
     }
+    drop(tx);
 
-    #[test]
-    fn test_single_true() {
-        let output = pipe(vec![vec!["/usr/bin/true"]]);
-        assert!(output.unwrap().eq(""));
+    let stdout_reader = spawned.stdout.take().map(|mut reader| {
+        thread::spawn(move || {
+            let mut stdout = Vec::new();
+            reader.read_to_end(&mut stdout).map(|_| stdout)
+        })
+    });
+
+    for line in rx {
+        on_stderr(&line);
     }
 
-    #[test]
-    fn test_single_false() {
-        let output = pipe(vec![vec!["/usr/bin/false"]]);
-        assert_eq!(output.as_ref().unwrap_err().kind(), ErrorKind::ExecError);
-        assert_eq!(output.as_ref().unwrap_err().code(), Some(1));
+    let stdout = match stdout_reader.map(|t| t.join()) {
+        Some(Ok(Ok(stdout))) => stdout,
+        Some(Ok(Err(e))) => {
+            spawned.kill();
+            return Err(Error::from_io(e));
+        }
+        Some(Err(_e)) => {
+            spawned.kill();
+            return Err(Error::new(
+                ErrorKind::UnknownError,
+                None,
+                "stdout reader panicked",
+            ));
+        }
+        None => Vec::new(),
+    };
+
+    let code = exit_code(spawned.wait()?)?;
+    Ok((decode_utf8(stdout)?, code))
+}
+
+// pipe_bytes()
+//_____________________________________________________________________________
+
+/// Execute a shell command pipeline and return stdout as raw bytes, without
+/// utf-8 decoding.
+///
+/// Errors are returned as with `pipe()`, except that UnicodeDecodeError is
+/// never returned.
+pub fn pipe_bytes(commands: Vec<Vec<&str>>) -> Result<Vec<u8>, Error> {
+    Pipeline::new(commands).output_bytes()
+}
+
+/// Execute a shell command pipeline and return stdout as a reference-counted
+/// `bytes::Bytes`, so it can be cheaply cloned and shared between tasks.
+///
+/// Errors are returned as with `pipe_bytes()`. Requires the `bytes` feature.
+#[cfg(feature = "bytes")]
+pub fn pipe_bytes_shared(commands: Vec<Vec<&str>>) -> Result<bytes::Bytes, Error> {
+    pipe_bytes(commands).map(bytes::Bytes::from)
+}
+
+// pipe_csv()
+//_____________________________________________________________________________
+
+/// Execute a shell command pipeline and parse stdout as CSV, returning each
+/// record as a Vec of fields. Every line is treated as a record, including any
+/// header, and quoted fields may contain commas and newlines. Records may have
+/// differing numbers of fields.
+///
+/// If stdout is not valid CSV or not valid utf-8, it will return a
+/// shutil::Error with kind() set to ParseError. Other errors are returned as
+/// with `pipe()`. Requires the `csv` feature.
+#[cfg(feature = "csv")]
+pub fn pipe_csv(commands: Vec<Vec<&str>>) -> Result<Vec<Vec<String>>, Error> {
+    let stdout = pipe_bytes(commands)?;
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(stdout.as_slice());
+
+    let mut rows = Vec::new();
+    for record in reader.records() {
+        match record {
+            Ok(record) => rows.push(record.iter().map(|field| field.to_string()).collect()),
+            Err(e) => return Err(Error::new(ErrorKind::ParseError, None, &e.to_string())),
+        }
     }
+    Ok(rows)
+}
 
-    #[test]
-    fn test_echo_one_arg() {
-        let output = pipe(vec![vec!["echo", "hello"]]);
-        assert!(output.unwrap().eq("hello\n"));
+// spawn_detached()
+//_____________________________________________________________________________
+
+/// Spawn a shell command pipeline that keeps running after the parent process
+/// exits, like `nohup cmd &`, and return the process ids of its commands. Each
+/// command is double-forked into a new session with stdin, stdout and stderr
+/// connected to /dev/null, so it is not tied to the parent's terminal. The
+/// commands are reparented to init, or the nearest subreaper, before this
+/// returns, and are never reaped by the parent.
+///
+/// Errors spawning the pipeline are returned as with `pipe()`.
+#[cfg(unix)]
+pub fn spawn_detached(commands: Vec<Vec<&str>>) -> Result<Vec<u32>, Error> {
+    let spawned = Pipeline::new(commands).detach().start()?;
+    Ok(spawned.detach())
+}
+
+// pipe_split_on()
+//_____________________________________________________________________________
+
+/// Execute a shell command pipeline and split the raw stdout on `delim`,
+/// returning each segment without the delimiter. A trailing empty segment,
+/// left by output that ends with the delimiter, is dropped. This handles
+/// record separators such as `\x1e` or `:`.
+///
+/// Errors are returned as with `pipe_bytes()`.
+pub fn pipe_split_on(delim: u8, commands: Vec<Vec<&str>>) -> Result<Vec<Vec<u8>>, Error> {
+    let stdout = pipe_bytes(commands)?;
+    let mut segments: Vec<Vec<u8>> = stdout.split(|&b| b == delim).map(|s| s.to_vec()).collect();
+    if segments.last().is_some_and(|s| s.is_empty()) {
+        segments.pop();
     }
+    Ok(segments)
+}
 
-    #[test]
-    fn test_echo_two_args() {
-        let output = pipe(vec![vec!["echo", "hello", "world"]]);
-        assert!(output.unwrap().eq("hello world\n"));
+// pipe_parse()
+//_____________________________________________________________________________
+
+/// Execute a shell command pipeline and pass its stdout to `parser` as a
+/// `BufRead` as it is produced, returning the parser's result. Any output the
+/// parser does not consume is read and discarded, so the pipeline still runs
+/// to completion.
+///
+/// An io::Error returned by the parser is converted to a shutil::Error with
+/// kind() set to OsError if it has a raw os error, or UnknownError otherwise.
+/// Other errors are returned as with `pipe()`.
+pub fn pipe_parse<T, F>(commands: Vec<Vec<&str>>, mut parser: F) -> Result<T, Error>
+where
+    F: FnMut(&mut dyn BufRead) -> io::Result<T>,
+{
+    let mut spawned = Pipeline::new(commands).start()?;
+    let stdout = spawned
+        .stdout
+        .take()
+        .unwrap_or_else(|| Box::new(io::empty()));
+    let mut reader = BufReader::new(stdout);
+
+    let result = match parser(&mut reader) {
+        Ok(v) => v,
+        Err(e) => {
+            spawned.kill();
+            return Err(Error::from_io(e));
+        }
+    };
+    if let Err(e) = io::copy(&mut reader, &mut io::sink()) {
+        spawned.kill();
+        return Err(Error::from_io(e));
     }
 
-    // two command pipe tests
+    check_status(spawned.wait()?)?;
+    Ok(result)
+}
 
-    #[test]
-    fn test_echo_rev() {
-        let output = pipe(vec![vec!["echo", "foo"], vec!["rev"]]);
-        let unwrapped = output.unwrap();
-        assert!(unwrapped.eq("oof\n"));
+// filter()
+//_____________________________________________________________________________
+
+/// Execute a shell command pipeline with the parent process's stdin connected
+/// to the stdin of the first command, and return stdout. This makes it easy to
+/// write unix filters that wrap other commands, such as a tool used as
+/// `producer | mytool`.
+///
+/// Errors are returned as with `pipe()`.
+pub fn filter(commands: Vec<Vec<&str>>) -> Result<String, Error> {
+    Pipeline::new(commands).inherit_stdin().output()
+}
+
+// pipe_retry()
+//_____________________________________________________________________________
+
+/// Execute a shell command pipeline up to `attempts` times until it succeeds,
+/// returning stdout from the first successful run. After each failure the
+/// call sleeps before retrying, starting at `backoff` and doubling after each
+/// attempt.
+///
+/// If `total_deadline` is set, it bounds the whole retry loop, including each
+/// attempt and the sleeps between them. Once the deadline has passed, or the
+/// next sleep would end after it, a shutil::Error with kind() set to
+/// TimeoutError is returned instead of starting another attempt, with the last
+/// failure as its source.
+///
+/// If every attempt fails, the error from the last attempt is returned.
+/// InvalidFormatError is returned immediately, since retrying cannot fix it.
+pub fn pipe_retry(
+    commands: Vec<Vec<&str>>,
+    attempts: u32,
+    backoff: Duration,
+    total_deadline: Option<Instant>,
+) -> Result<String, Error> {
+    let mut delay = backoff;
+    let mut attempt = 0;
+    loop {
+        let mut pipeline = Pipeline::new(commands.clone());
+        if let Some(deadline) = total_deadline {
+            pipeline = pipeline.deadline(deadline);
+        }
+        let err = match pipeline.output() {
+            Ok(output) => return Ok(output),
+            Err(e) if e.kind() == ErrorKind::InvalidFormatError => return Err(e),
+            Err(e) => e,
+        };
+
+        attempt += 1;
+        if attempt >= attempts {
+            return Err(err);
+        }
+        if let Some(deadline) = total_deadline {
+            if err.kind() == ErrorKind::TimeoutError || Instant::now() + delay >= deadline {
+                return Err(Error::new(
+                    ErrorKind::TimeoutError,
+                    None,
+                    format!("retry deadline exceeded after {} attempts", attempt).as_str(),
+                )
+                .with_source(err));
+            }
+        }
+        thread::sleep(delay);
+        delay *= 2;
     }
+}
 
-    // three command pipe tests
+// pipe_observe()
+//_____________________________________________________________________________
 
-    #[test]
-    fn test_echo_rev_tr() {
-        let output = pipe(vec![
-            vec!["echo", "foo"],
-            vec!["rev"],
-            vec!["tr", "a-z", "A-Z"],
-        ]);
-        let unwrapped = output.unwrap();
+/// Execute a shell command pipeline, calling `on_stdout` with each line of
+/// stdout and `on_stderr` with each line written to stderr by any stage as
+/// they arrive, and return the exit code of the final command. Both streams
+/// are read concurrently, so a command writing heavily to either one cannot
+/// deadlock the pipeline. Lines are passed without the trailing newline, and
+/// invalid utf-8 is replaced with U+FFFD.
+///
+/// Lines from each stream are delivered in order, but the relative order of
+/// stdout and stderr lines depends on when the commands flush them.
+///
+/// A non-zero exit code is returned as Ok. If the final command is killed by a
+/// signal, a shutil::Error with kind() set to ExecError and code() set to None
+/// is returned. Other errors are returned as with `pipe()`.
+pub fn pipe_observe<F, G>(
+    commands: Vec<Vec<&str>>,
+    mut on_stdout: F,
+    mut on_stderr: G,
+) -> Result<i32, Error>
+where
+    F: FnMut(&str),
+    G: FnMut(&str),
+{
+    let mut spawned = Pipeline::new(commands).pipe_stderr().start()?;
+
+    let (tx, rx) = mpsc::channel();
+    let mut readers: Vec<(bool, Box<dyn Read + Send>)> = Vec::new();
+    if let Some(stdout) = spawned.stdout.take() {
+        readers.push((true, stdout));
+    }
+    for stderr in spawned.stderr.drain(..) {
+        readers.push((false, Box::new(stderr)));
+    }
+    let threads: Vec<JoinHandle<io::Result<()>>> = readers
+        .into_iter()
+        .map(|(is_stdout, reader)| {
+            let tx = tx.clone();
+            thread::spawn(move || {
+                for line in BufReader::new(reader).split(b'\n') {
+                    let line = String::from_utf8_lossy(&line?).into_owned();
+                    if tx.send((is_stdout, line)).is_err() {
+                        break;
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    drop(tx);
+
+    for (is_stdout, line) in rx {
+        if is_stdout {
+            on_stdout(&line);
+        } else {
+            on_stderr(&line);
+        }
+    }
+
+    for t in threads {
+        let result = match t.join() {
+            Ok(result) => result.map_err(Error::from_io),
+            Err(_e) => Err(Error::new(
+                ErrorKind::UnknownError,
+                None,
+                "output reader panicked",
+            )),
+        };
+        if let Err(e) = result {
+            spawned.kill();
+            return Err(e);
+        }
+    }
+
+    exit_code(spawned.wait()?)
+}
+
+// pipe_with_rusage()
+//_____________________________________________________________________________
+
+/// Execute a shell command pipeline and return stdout along with the resource
+/// usage of the final command, including its peak resident set size and CPU
+/// time. This is useful for finding memory-hungry commands.
+///
+/// Errors are returned as with `pipe()`.
+#[cfg(target_os = "linux")]
+pub fn pipe_with_rusage(commands: Vec<Vec<&str>>) -> Result<(String, RUsage), Error> {
+    let mut spawned = Pipeline::new(commands).start()?;
+    let stdout = spawned.read_to_end(None)?;
+    let (status, usage) = spawned.wait_rusage()?;
+    check_status(status)?;
+    Ok((decode_utf8(stdout)?, usage))
+}
+
+// source()
+//_____________________________________________________________________________
+
+/// A chain of commands built by `source()`, which is fed its input on stdin
+/// when collected.
+#[derive(Clone, Debug)]
+pub struct Stream {
+    input: String,
+    commands: Vec<Vec<String>>,
+}
+
+impl Stream {
+    /// Append a command to the chain. Its stdin is the stdout of the previous
+    /// command, or the input passed to `source()` for the first command.
+    pub fn pipe(mut self, command: Vec<&str>) -> Stream {
+        self.commands
+            .push(command.iter().map(|s| s.to_string()).collect());
+        self
+    }
+
+    /// Execute the chain as a single pipeline and return stdout. With no
+    /// commands, the input is returned unchanged.
+    ///
+    /// Errors are returned as with `pipe()`.
+    pub fn collect(self) -> Result<String, Error> {
+        if self.commands.is_empty() {
+            return Ok(self.input);
+        }
+        let commands = self
+            .commands
+            .iter()
+            .map(|c| c.iter().map(|s| s.as_str()).collect())
+            .collect();
+        Pipeline::new(commands)
+            .stdin_reader(io::Cursor::new(self.input.into_bytes()))
+            .output()
+    }
+}
+
+/// Start a chain of commands that transforms `input`, as a fluent alternative
+/// to building the whole pipeline upfront. For example,
+/// `source("foo").pipe(vec!["rev"]).pipe(vec!["tr", "a-z", "A-Z"]).collect()`
+/// returns "OOF".
+pub fn source(input: &str) -> Stream {
+    Stream {
+        input: input.to_string(),
+        commands: Vec::new(),
+    }
+}
+
+// is_builtin()
+//_____________________________________________________________________________
+
+/// POSIX shell builtins that act on the shell itself, so running them as a
+/// separate process has no useful effect.
+const SHELL_BUILTINS: &[&str] = &[
+    ".", ":", "alias", "bg", "break", "cd", "command", "continue", "eval", "exec", "exit",
+    "export", "fc", "fg", "getopts", "hash", "jobs", "read", "readonly", "return", "set", "shift",
+    "source", "times", "trap", "type", "ulimit", "umask", "unalias", "unset", "wait",
+];
+
+/// Return true if `name` is a common POSIX shell builtin, such as `cd` or
+/// `export`. These change the state of the shell itself, so a pipeline like
+/// `pipe(vec![vec!["cd", "/tmp"]])` will not behave as it would in a shell,
+/// and tooling can use this to warn about it.
+///
+/// Utilities that shells implement as builtins but which are also available
+/// as standalone binaries, such as `echo` and `test`, return false.
+pub fn is_builtin(name: &str) -> bool {
+    SHELL_BUILTINS.contains(&name)
+}
+
+// pipe_reader()
+//_____________________________________________________________________________
+
+/// Spawn a shell command pipeline and return a reader for its stdout, which
+/// can be passed to `io::copy()` or any other `Read` based API. A non-zero exit
+/// code is reported by the read after the end of the output.
+///
+/// Errors spawning the pipeline are returned as with `pipe()`.
+pub fn pipe_reader(commands: Vec<Vec<&str>>) -> Result<PipelineReader, Error> {
+    Ok(PipelineReader::new(Pipeline::new(commands).spawn()?))
+}
+
+// pipe_or()
+//_____________________________________________________________________________
+
+/// Execute a shell command pipeline and return its stdout with leading and
+/// trailing whitespace trimmed, or `default` if the pipeline fails for any
+/// reason. This is meant for best-effort lookups, such as `git describe` in a
+/// directory that may not be a repository.
+pub fn pipe_or(default: &str, commands: Vec<Vec<&str>>) -> String {
+    match pipe(commands) {
+        Ok(output) => output.trim().to_string(),
+        Err(_e) => default.to_string(),
+    }
+}
+
+// which()
+//_____________________________________________________________________________
+
+/// Return the path of the executable `name` in the directories listed in the
+/// PATH environment variable, or None if it is not found. On Unix, only files
+/// with an execute permission bit set count. Paths that are found are cached
+/// per name and value of PATH, so repeated lookups in a loop do not search the
+/// filesystem again. The same cache is used to find the commands of a
+/// pipeline with `Pipeline::cache_binary_paths()` set.
+pub fn which(name: &str) -> Option<PathBuf> {
+    find_in_path_cached(name)
+}
+
+/// Forget every path cached by `which()`, so the next lookup searches PATH
+/// again. Call this after installing or removing binaries.
+pub fn clear_binary_cache() {
+    pipeline::clear_binary_cache();
+}
+
+// pipe_tail_lines()
+//_____________________________________________________________________________
+
+/// Execute a shell command pipeline and return the last `n` lines of stdout,
+/// without the trailing newlines. Earlier lines are dropped as they are read,
+/// so memory use is bounded by the size of the last `n` lines rather than the
+/// whole output.
+///
+/// Only the returned lines are decoded, so a UnicodeDecodeError is returned
+/// if one of them is not valid utf-8. Other errors are returned as with
+/// `pipe()`.
+pub fn pipe_tail_lines(n: usize, commands: Vec<Vec<&str>>) -> Result<Vec<String>, Error> {
+    let mut spawned = Pipeline::new(commands).start()?;
+    let mut lines = VecDeque::with_capacity(n);
+
+    if let Some(stdout) = spawned.stdout.take() {
+        for line in BufReader::new(stdout).split(b'\n') {
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => {
+                    spawned.kill();
+                    return Err(Error::from_io(e));
+                }
+            };
+            if n == 0 {
+                continue;
+            }
+            if lines.len() == n {
+                lines.pop_front();
+            }
+            lines.push_back(line);
+        }
+    }
+
+    check_status(spawned.wait()?)?;
+    lines.into_iter().map(decode_utf8).collect()
+}
+
+// check()
+//_____________________________________________________________________________
+
+// Whether capture-less helpers warn about discarded output, and whether the
+// warning has already been printed.
+static DISCARD_WARNING: AtomicBool = AtomicBool::new(false);
+static DISCARD_WARNED: AtomicBool = AtomicBool::new(false);
+
+/// Enable or disable a warning, printed to stderr at most once, when a helper
+/// that does not capture output, such as `check()` or `pipe_code()`, discards
+/// non-empty stdout. This is meant for development, to catch calls that should
+/// have used a capturing function like `pipe()`. Enabling the warning re-arms
+/// it if it has already been printed.
+///
+/// While enabled, the discarded output is read and counted instead of being
+/// sent to /dev/null.
+pub fn set_discard_warning(enabled: bool) {
+    DISCARD_WARNED.store(false, Ordering::SeqCst);
+    DISCARD_WARNING.store(enabled, Ordering::SeqCst);
+}
+
+/// Execute a shell command pipeline with stdout discarded, returning Ok if the
+/// final command exits with code 0.
+///
+/// Errors are returned as with `pipe()`.
+pub fn check(commands: Vec<Vec<&str>>) -> Result<(), Error> {
+    check_status(run_discarding(commands)?)
+}
+
+// Run a pipeline with stdout discarded and return the final command's status,
+// warning on stderr about non-empty output if set_discard_warning() is enabled.
+fn run_discarding(commands: Vec<Vec<&str>>) -> Result<ExitStatus, Error> {
+    run_discarding_to(commands, |warning| eprintln!("{}", warning))
+}
+
+// As run_discarding(), passing any warning to `warn` instead.
+fn run_discarding_to(
+    commands: Vec<Vec<&str>>,
+    mut warn: impl FnMut(&str),
+) -> Result<ExitStatus, Error> {
+    if !DISCARD_WARNING.load(Ordering::SeqCst) {
+        return Pipeline::new(commands).discard_stdout().start()?.wait();
+    }
+
+    let description = describe_commands(&commands);
+    let mut spawned = Pipeline::new(commands).start()?;
+    let mut discarded = 0;
+    if let Some(mut stdout) = spawned.stdout.take() {
+        discarded = match io::copy(&mut stdout, &mut io::sink()) {
+            Ok(n) => n,
+            Err(e) => {
+                spawned.kill();
+                return Err(Error::from_io(e));
+            }
+        };
+    }
+    if let Some(warning) = discard_warning(&description, discarded) {
+        if !DISCARD_WARNED.swap(true, Ordering::SeqCst) {
+            warn(&warning);
+        }
+    }
+    spawned.wait()
+}
+
+// Return the warning for `discarded` bytes of output, or None if nothing was
+// discarded.
+fn discard_warning(description: &str, discarded: u64) -> Option<String> {
+    if discarded == 0 {
+        return None;
+    }
+    Some(format!(
+        "shutil: discarded {} bytes of output from `{}`; use a capturing function such as pipe() to keep it",
+        discarded, description
+    ))
+}
+
+// Join the commands of a pipeline into a shell-like string for messages.
+fn describe_commands(commands: &[Vec<&str>]) -> String {
+    commands
+        .iter()
+        .map(|c| c.join(" "))
+        .collect::<Vec<_>>()
+        .join(" | ")
+}
+
+// run_env_chain()
+//_____________________________________________________________________________
+
+/// Execute a sequence of commands, storing the trimmed stdout of each one in
+/// an environment variable named by its step, which is set for every later
+/// step. This models a chain of `VAR=$(cmd)` assignments in a shell script.
+/// Returns every variable that was set. If two steps use the same name, the
+/// later value replaces the earlier one.
+///
+/// The chain stops at the first step that fails, and its error is returned as
+/// with `pipe()`.
+pub fn run_env_chain(steps: Vec<(String, Vec<&str>)>) -> Result<HashMap<String, String>, Error> {
+    let mut vars: HashMap<String, String> = HashMap::new();
+    for (name, command) in steps {
+        let mut pipeline = Pipeline::new(vec![command]);
+        for (key, value) in &vars {
+            pipeline = pipeline.env(key, value);
+        }
+        let output = pipeline.output()?;
+        vars.insert(name, output.trim().to_string());
+    }
+    Ok(vars)
+}
+
+// require_version()
+//_____________________________________________________________________________
+
+/// Run `binary version_arg`, such as `git --version`, and check that the
+/// version it reports is at least `min`, returning the reported version. The
+/// version is the first dotted number like `2.31.4` found in stdout, or in
+/// stderr if stdout has none. Versions are compared numerically component by
+/// component, with missing components treated as 0, so `2.31` satisfies a
+/// minimum of `2.31.0`.
+///
+/// If the version is below `min`, a shutil::Error with kind() set to ExecError
+/// is returned. If no version is found in the output, the kind() is set to
+/// ParseError, and if `min` is not a dotted number it is InvalidFormatError.
+/// Errors running the command are returned as with `pipe()`.
+pub fn require_version(binary: &str, version_arg: &str, min: &str) -> Result<String, Error> {
+    let min_parts = match parse_version(min) {
+        Some(parts) => parts,
+        None => {
+            return Err(Error::new(
+                ErrorKind::InvalidFormatError,
+                Some(-1),
+                format!("invalid minimum version {:?}", min).as_str(),
+            ));
+        }
+    };
+
+    let result = Pipeline::new(vec![vec![binary, version_arg]])
+        .io(IoConfig {
+            stdin: StdinMode::Null,
+            stdout: StdoutMode::Capture,
+            stderr: StderrMode::Capture,
+        })
+        .exec()?;
+    if result.code != Some(0) {
+        return Err(
+            Error::new(ErrorKind::ExecError, result.code, "non-zero exit code")
+                .with_stderr(result.stderr),
+        );
+    }
+
+    let version = match find_version(&result.stdout).or_else(|| find_version(&result.stderr)) {
+        Some(version) => version,
+        None => {
+            return Err(Error::new(
+                ErrorKind::ParseError,
+                None,
+                format!("no version found in output of {}", binary).as_str(),
+            ));
+        }
+    };
+
+    // Both sides are already known to parse.
+    let parts = parse_version(version).unwrap_or_default();
+    let len = parts.len().max(min_parts.len());
+    let pad = |v: &[u64]| {
+        (0..len)
+            .map(|i| *v.get(i).unwrap_or(&0))
+            .collect::<Vec<_>>()
+    };
+    if pad(&parts) < pad(&min_parts) {
+        return Err(Error::new(
+            ErrorKind::ExecError,
+            None,
+            format!("{} version {} is older than {}", binary, version, min).as_str(),
+        ));
+    }
+    Ok(version.to_string())
+}
+
+// Return the first dotted number, like "1.2.3", in `text`.
+fn find_version(text: &str) -> Option<&str> {
+    text.split(|c: char| !c.is_ascii_digit() && c != '.')
+        .map(|word| word.trim_matches('.'))
+        .find(|word| word.contains('.') && parse_version(word).is_some())
+}
+
+// Split a dotted number into its components.
+fn parse_version(version: &str) -> Option<Vec<u64>> {
+    version.split('.').map(|part| part.parse().ok()).collect()
+}
+
+// pipe_to_file_atomic()
+//_____________________________________________________________________________
+
+/// Execute a shell command pipeline and write its stdout to `path` atomically.
+/// Output is written to a temporary file in the same directory, which is
+/// renamed over `path` only if the pipeline succeeds, so readers never see a
+/// partially written file. If the pipeline fails, the temporary file is
+/// removed and `path` is left untouched.
+///
+/// Errors are returned as with `pipe()`. Failing to create or rename the
+/// temporary file is returned as an OsError.
+pub fn pipe_to_file_atomic(path: &Path, commands: Vec<Vec<&str>>) -> Result<(), Error> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let name = match path.file_name() {
+        Some(name) => name.to_string_lossy(),
+        None => {
+            return Err(Error::new(
+                ErrorKind::InvalidFormatError,
+                Some(-1),
+                format!("{} is not a file path", path.display()).as_str(),
+            ));
+        }
+    };
+    let temp = path.with_file_name(format!(
+        ".{}.shutil-{}-{}.tmp",
+        name,
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::SeqCst)
+    ));
+
+    let result = Pipeline::new(commands)
+        .io(IoConfig {
+            stdout: StdoutMode::File(temp.clone()),
+            ..IoConfig::default()
+        })
+        .exec()
+        .and_then(|result| match result.code {
+            Some(0) => Ok(()),
+            code => Err(Error::new(ErrorKind::ExecError, code, "non-zero exit code")),
+        })
+        // Flush the data to disk first, so a crash cannot leave `path`
+        // replaced by an incomplete file.
+        .and_then(|()| {
+            std::fs::File::open(&temp)
+                .and_then(|file| file.sync_all())
+                .and_then(|()| std::fs::rename(&temp, path))
+                .map_err(Error::from_io)
+        });
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp);
+    }
+    result
+}
+
+// pipe_interactive_handle()
+//_____________________________________________________________________________
+
+/// Spawn a shell command pipeline for interactive use, returning the first
+/// command's stdin, a reader for the final command's stdout and a handle to
+/// the running pipeline. Requests can be written to stdin and responses read
+/// back for as long as the pipeline runs, as with a coprocess such as `bc`.
+///
+/// Dropping stdin closes it, which lets most commands exit. The reader
+/// returns end of file once the output ends, and `PipelineHandle::wait()`
+/// reports the exit status. Dropping the handle kills the pipeline.
+///
+/// Errors spawning the pipeline are returned as with `pipe()`.
+pub fn pipe_interactive_handle(
+    commands: Vec<Vec<&str>>,
+) -> Result<(ChildStdin, PipelineReader, PipelineHandle), Error> {
+    let mut handle = Pipeline::new(commands).pipe_stdin().spawn()?;
+    match handle.take_stdio() {
+        (Some(stdin), reader) => Ok((stdin, reader, handle)),
+        (None, _reader) => Err(Error::new(
+            ErrorKind::UnknownError,
+            None,
+            "stdin was not piped",
+        )),
+    }
+}
+
+// pipe_map_inputs()
+//_____________________________________________________________________________
+
+/// Run a pipeline for each of `inputs` concurrently and return the results in
+/// input order. `make_commands` builds the pipeline for one input, for example
+/// `|file| vec![vec!["gzip".into(), "-t".into(), file.into()]]`. At most one
+/// pipeline per available CPU runs at a time.
+///
+/// Each result is returned as with `pipe()`, so one input failing does not
+/// stop the others.
+pub fn pipe_map_inputs<F>(inputs: Vec<String>, make_commands: F) -> Vec<Result<String, Error>>
+where
+    F: Fn(&str) -> Vec<Vec<String>> + Sync,
+{
+    let workers = thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+        .min(inputs.len());
+    let next = AtomicUsize::new(0);
+    let (tx, rx) = mpsc::channel();
+
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            let tx = tx.clone();
+            let (inputs, next, make_commands) = (&inputs, &next, &make_commands);
+            scope.spawn(move || loop {
+                let index = next.fetch_add(1, Ordering::SeqCst);
+                let input = match inputs.get(index) {
+                    Some(input) => input,
+                    None => break,
+                };
+                let commands = make_commands(input);
+                let commands = commands
+                    .iter()
+                    .map(|c| c.iter().map(|s| s.as_str()).collect())
+                    .collect();
+                if tx.send((index, pipe(commands))).is_err() {
+                    break;
+                }
+            });
+        }
+    });
+    drop(tx);
+
+    let mut results: Vec<(usize, Result<String, Error>)> = rx.into_iter().collect();
+    results.sort_by_key(|(index, _result)| *index);
+    results.into_iter().map(|(_index, result)| result).collect()
+}
+
+// pipe_expect_stderr()
+//_____________________________________________________________________________
+
+/// Execute a shell command pipeline and return stdout, checking that the
+/// combined stderr of every stage contains `pattern`. The stderr is captured
+/// rather than inherited. This is useful for testing that a tool emits the
+/// expected diagnostics. Invalid utf-8 in either stream is replaced with
+/// U+FFFD.
+///
+/// If stderr does not contain `pattern`, a shutil::Error with kind() set to
+/// ExecError is returned with the captured stderr attached. Other errors are
+/// returned as with `pipe()`, also with the captured stderr attached.
+pub fn pipe_expect_stderr(pattern: &str, commands: Vec<Vec<&str>>) -> Result<String, Error> {
+    let result = Pipeline::new(commands)
+        .io(IoConfig {
+            stderr: StderrMode::Capture,
+            ..IoConfig::default()
+        })
+        .exec()?;
+    if result.code != Some(0) {
+        return Err(
+            Error::new(ErrorKind::ExecError, result.code, "non-zero exit code")
+                .with_stderr(result.stderr),
+        );
+    }
+    if !result.stderr.contains(pattern) {
+        return Err(Error::new(
+            ErrorKind::ExecError,
+            result.code,
+            format!("stderr does not contain {:?}", pattern).as_str(),
+        )
+        .with_stderr(result.stderr));
+    }
+    Ok(result.stdout)
+}
+
+// to_script()
+//_____________________________________________________________________________
+
+/// Return a self-contained POSIX sh script that runs `commands` as a pipeline,
+/// after changing to `cwd` if given and exporting each of `env`. Every word is
+/// escaped for the shell, so the script runs exactly the commands that
+/// `pipe()` would, and can be saved or handed off to be run elsewhere.
+///
+/// A name in `env` that is not a valid shell variable name, or a `cwd` that is
+/// not valid UTF-8, returns a shutil::Error with kind() set to
+/// InvalidFormatError, since it cannot be written into the script as is.
+pub fn to_script(
+    commands: Vec<Vec<&str>>,
+    env: &[(&str, &str)],
+    cwd: Option<&Path>,
+) -> Result<String, Error> {
+    let mut script = String::from("#!/bin/sh\n");
+    if let Some(cwd) = cwd {
+        let cwd = cwd.to_str().ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidFormatError,
+                Some(-1),
+                format!("{} is not valid utf-8", cwd.display()).as_str(),
+            )
+        })?;
+        script.push_str(&format!("cd {} || exit 1\n", shell_escape(cwd)));
+    }
+    for (name, value) in env {
+        if !is_shell_name(name) {
+            return Err(Error::new(
+                ErrorKind::InvalidFormatError,
+                Some(-1),
+                format!("invalid variable name {:?}", name).as_str(),
+            ));
+        }
+        script.push_str(&format!("export {}={}\n", name, shell_escape(value)));
+    }
+    let pipeline = commands
+        .iter()
+        .map(|c| {
+            c.iter()
+                .map(|w| shell_escape(w))
+                .collect::<Vec<_>>()
+                .join(" ")
+        })
+        .collect::<Vec<_>>()
+        .join(" | ");
+    script.push_str(&pipeline);
+    script.push('\n');
+    Ok(script)
+}
+
+// Whether `name` matches `[A-Za-z_][A-Za-z0-9_]*`.
+fn is_shell_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// pipe_summary()
+//_____________________________________________________________________________
+
+/// Execute a shell command pipeline and return the exit code of the final
+/// command along with whether it wrote anything to stdout. The output itself
+/// is read and dropped rather than kept. A non-zero exit code is returned as
+/// Ok rather than treated as an error.
+///
+/// Errors are returned as with `pipe_code()`.
+pub fn pipe_summary(commands: Vec<Vec<&str>>) -> Result<(i32, bool), Error> {
+    let mut spawned = Pipeline::new(commands).start()?;
+    let mut produced = 0;
+    if let Some(mut stdout) = spawned.stdout.take() {
+        produced = match io::copy(&mut stdout, &mut io::sink()) {
+            Ok(n) => n,
+            Err(e) => {
+                spawned.kill();
+                return Err(Error::from_io(e));
+            }
+        };
+    }
+    Ok((exit_code(spawned.wait()?)?, produced > 0))
+}
+
+// pipe_with_fallback()
+//_____________________________________________________________________________
+
+/// Execute the `primary` shell command pipeline and return its stdout if it
+/// finishes within `budget`. Otherwise every stage of the primary is killed and
+/// the `fallback` pipeline is run instead, without a budget, and its stdout is
+/// returned. This suits latency-critical lookups with a cheaper but less
+/// precise alternative.
+///
+/// Only a timeout falls back. Any other error from the primary, such as a
+/// non-zero exit code, is returned as with `pipe()`, as are errors from the
+/// fallback.
+pub fn pipe_with_fallback(
+    primary: Vec<Vec<&str>>,
+    budget: Duration,
+    fallback: Vec<Vec<&str>>,
+) -> Result<String, Error> {
+    match Pipeline::new(primary)
+        .deadline(Instant::now() + budget)
+        .output()
+    {
+        Err(e) if e.kind() == ErrorKind::TimeoutError => pipe(fallback),
+        result => result,
+    }
+}
+
+// Tests
+//_____________________________________________________________________________
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::Write;
+
+    // Zero command tests
+
+    #[test]
+    fn test_no_commands() {
+        let output = pipe(vec![]);
+
+        assert_eq!(
+            output.as_ref().unwrap_err().kind(),
+            ErrorKind::InvalidFormatError
+        );
+        assert_eq!(output.as_ref().unwrap_err().code(), Some(-1));
+    }
+
+    #[test]
+    fn test_no_binary() {
+        let output = pipe(vec![vec![]]);
+
+        assert_eq!(
+            output.as_ref().unwrap_err().kind(),
+            ErrorKind::InvalidFormatError
+        );
+        assert_eq!(output.as_ref().unwrap_err().code(), Some(-1));
+    }
+
+    // Single command tests
+
+    #[test]
+    fn test_single_not_found() {
+        let output = pipe(vec![vec!["/does/not/exist"]]);
+
+        assert_eq!(output.as_ref().unwrap_err().kind(), ErrorKind::OsError);
+
+        // errno 2 is ENOENT No such file or directory.
+        assert_eq!(output.as_ref().unwrap_err().code(), Some(2));
+    }
+
+    #[test]
+    #[allow(clippy::comparison_to_empty)]
+    fn test_single_true() {
+        let output = pipe(vec![vec!["/usr/bin/true"]]);
+        assert!(output.unwrap().eq(""));
+    }
+
+    #[test]
+    fn test_single_false() {
+        let output = pipe(vec![vec!["/usr/bin/false"]]);
+        assert_eq!(output.as_ref().unwrap_err().kind(), ErrorKind::ExecError);
+        assert_eq!(output.as_ref().unwrap_err().code(), Some(1));
+    }
+
+    #[test]
+    fn test_echo_one_arg() {
+        let output = pipe(vec![vec!["echo", "hello"]]);
+        assert!(output.unwrap().eq("hello\n"));
+    }
+
+    #[test]
+    fn test_echo_two_args() {
+        let output = pipe(vec![vec!["echo", "hello", "world"]]);
+        assert!(output.unwrap().eq("hello world\n"));
+    }
+
+    #[test]
+    fn test_invalid_utf8_prefix() {
+        let output = pipe(vec![vec!["printf", "caf\\303\\251\\nok\\377rest"]]);
+        let err = output.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnicodeDecodeError);
+        assert_eq!(err.valid_prefix(), Some("café\nok"));
+
+        let err = pipe(vec![vec!["/usr/bin/false"]]).unwrap_err();
+        assert_eq!(err.valid_prefix(), None);
+    }
+
+    // two command pipe tests
+
+    #[test]
+    fn test_echo_rev() {
+        let output = pipe(vec![vec!["echo", "foo"], vec!["rev"]]);
+        let unwrapped = output.unwrap();
+        assert!(unwrapped.eq("oof\n"));
+    }
+
+    // three command pipe tests
+
+    #[test]
+    fn test_echo_rev_tr() {
+        let output = pipe(vec![
+            vec!["echo", "foo"],
+            vec!["rev"],
+            vec!["tr", "a-z", "A-Z"],
+        ]);
+        let unwrapped = output.unwrap();
         assert!(unwrapped.eq("OOF\n"));
     }
+
+    // pipe_diff() tests
+
+    #[test]
+    fn test_pipe_diff_same() {
+        let diff = pipe_diff(vec![vec!["echo", "a"]], vec![vec!["echo", "a"]]);
+        assert_eq!(diff.unwrap(), None);
+    }
+
+    #[test]
+    fn test_pipe_diff_different() {
+        let diff = pipe_diff(vec![vec!["echo", "a"]], vec![vec!["echo", "b"]]);
+        assert_eq!(
+            diff.unwrap().unwrap(),
+            "--- a\n+++ b\n@@ -1,1 +1,1 @@\n-a\n+b\n"
+        );
+    }
+
+    #[test]
+    fn test_pipe_diff_missing_newline() {
+        let diff = pipe_diff(vec![vec!["printf", "a"]], vec![vec!["printf", "a\\n"]]);
+        assert_eq!(
+            diff.unwrap().unwrap(),
+            "--- a\n+++ b\n@@ -1,1 +1,1 @@\n-a\n\\ No newline at end of file\n+a\n"
+        );
+    }
+
+    #[test]
+    fn test_pipe_diff_empty_side() {
+        let diff = pipe_diff(vec![vec!["true"]], vec![vec!["echo", "a"]]);
+        assert_eq!(
+            diff.unwrap().unwrap(),
+            "--- a\n+++ b\n@@ -0,0 +1,1 @@\n+a\n"
+        );
+    }
+
+    #[test]
+    fn test_pipe_diff_error() {
+        let diff = pipe_diff(vec![vec!["echo", "a"]], vec![vec!["/usr/bin/false"]]);
+        assert_eq!(diff.unwrap_err().kind(), ErrorKind::ExecError);
+    }
+
+    // pipe_latency() tests
+
+    #[test]
+    fn test_pipe_latency() {
+        let (output, stats) = pipe_latency(vec![vec!["sh", "-c", "sleep 0.3; echo done"]]).unwrap();
+        assert_eq!(output, "done\n");
+
+        let first_byte = stats.first_byte.unwrap();
+        assert!(first_byte >= stats.spawn + Duration::from_millis(200));
+        assert!(stats.total >= first_byte);
+    }
+
+    #[test]
+    fn test_pipe_latency_no_output() {
+        let (output, stats) = pipe_latency(vec![vec!["true"]]).unwrap();
+        assert!(output.is_empty());
+        assert_eq!(stats.first_byte, None);
+    }
+
+    // pipe_until() tests
+
+    #[test]
+    fn test_pipe_until_match() {
+        let start = Instant::now();
+        let output = pipe_until(
+            vec![vec![
+                "sh",
+                "-c",
+                "echo starting; echo Ready; sleep 5; echo done",
+            ]],
+            |line| line == "Ready",
+        );
+        assert_eq!(output.unwrap(), "starting\nReady\n");
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_pipe_until_no_match() {
+        let output = pipe_until(vec![vec!["echo", "foo"]], |line| line == "Ready");
+        assert_eq!(output.unwrap(), "foo\n");
+    }
+
+    // pipe_lossy_flagged() tests
+
+    #[test]
+    fn test_pipe_lossy_flagged_valid() {
+        let output = pipe_lossy_flagged(vec![vec!["echo", "foo"]]);
+        assert_eq!(output.unwrap(), ("foo\n".to_string(), false));
+    }
+
+    #[test]
+    fn test_pipe_lossy_flagged_invalid() {
+        let output = pipe_lossy_flagged(vec![vec!["printf", "a\\377b"]]);
+        assert_eq!(output.unwrap(), ("a\u{FFFD}b".to_string(), true));
+    }
+
+    // pipe_supervise() tests
+
+    #[test]
+    fn test_pipe_supervise_restarts() {
+        let exits = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&exits);
+        let handle = pipe_supervise(vec![vec!["/usr/bin/false"]], move |code| {
+            assert_eq!(code, Some(1));
+            counter.fetch_add(1, Ordering::SeqCst) < 2
+        })
+        .unwrap();
+
+        handle.wait().unwrap();
+        assert_eq!(exits.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_pipe_supervise_stop() {
+        let handle = pipe_supervise(vec![vec!["sleep", "10"]], |_code| true).unwrap();
+        let start = Instant::now();
+        handle.stop().unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_pipe_supervise_respawn_error() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // The script removes itself, so it can only be spawned once.
+        let script = env::temp_dir().join(format!("shutil-supervise-{}", std::process::id()));
+        std::fs::write(&script, "#!/bin/sh\nrm \"$0\"\n").unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let handle = pipe_supervise(vec![vec![script.to_str().unwrap()]], |_code| true).unwrap();
+        let err = handle.wait().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::OsError);
+        assert!(!script.exists());
+    }
+
+    #[test]
+    fn test_pipe_supervise_drop() {
+        let exits = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&exits);
+        let handle = pipe_supervise(vec![vec!["true"]], move |_code| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            true
+        })
+        .unwrap();
+        thread::sleep(Duration::from_millis(50));
+        // Dropping the handle waits for the supervisor thread to exit, so the
+        // pipeline is not respawned afterwards.
+        drop(handle);
+        let stopped_at = exits.load(Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(exits.load(Ordering::SeqCst), stopped_at);
+    }
+
+    // pipe_byte_count() tests
+
+    #[test]
+    fn test_pipe_byte_count() {
+        let count = pipe_byte_count(vec![vec!["head", "-c", "100000", "/dev/zero"]]);
+        assert_eq!(count.unwrap(), 100000);
+    }
+
+    #[test]
+    fn test_pipe_byte_count_error() {
+        let count = pipe_byte_count(vec![vec!["/usr/bin/false"]]);
+        assert_eq!(count.unwrap_err().kind(), ErrorKind::ExecError);
+    }
+
+    // poll_until_changed() tests
+
+    #[test]
+    fn test_poll_until_changed() {
+        let path = std::env::temp_dir().join(format!("shutil-poll-{}", std::process::id()));
+        fs::write(&path, "0\n").unwrap();
+
+        let writer_path = path.clone();
+        let writer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            fs::write(&writer_path, "1\n").unwrap();
+        });
+
+        let path_str = path.to_str().unwrap();
+        let output =
+            poll_until_changed(vec![vec!["cat", path_str]], Duration::from_millis(20), 100);
+        writer.join().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(output.unwrap(), "1\n");
+    }
+
+    #[test]
+    fn test_poll_until_changed_timeout() {
+        let output = poll_until_changed(vec![vec!["echo", "a"]], Duration::from_millis(1), 3);
+        assert_eq!(output.unwrap_err().kind(), ErrorKind::TimeoutError);
+    }
+
+    // pipe_reencode() tests
+
+    #[cfg(feature = "encoding")]
+    #[test]
+    fn test_pipe_reencode_latin1_to_utf8() {
+        let output = pipe_reencode(
+            encoding_rs::WINDOWS_1252,
+            encoding_rs::UTF_8,
+            vec![vec!["printf", "caf\\351"]],
+        );
+        assert_eq!(output.unwrap(), "café".as_bytes());
+    }
+
+    #[cfg(feature = "encoding")]
+    #[test]
+    fn test_pipe_reencode_unmappable() {
+        let output = pipe_reencode(
+            encoding_rs::UTF_8,
+            encoding_rs::WINDOWS_1252,
+            vec![vec!["echo", "日本"]],
+        );
+        assert_eq!(output.unwrap_err().kind(), ErrorKind::UnicodeDecodeError);
+    }
+
+    // pipe_code() tests
+
+    #[test]
+    fn test_pipe_code_true() {
+        assert_eq!(pipe_code(vec![vec!["true"]]).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_pipe_code_false() {
+        assert_eq!(pipe_code(vec![vec!["false"]]).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_pipe_code_not_found() {
+        let code = pipe_code(vec![vec!["/does/not/exist"]]);
+        assert_eq!(code.unwrap_err().kind(), ErrorKind::OsError);
+    }
+
+    // pipe_then() tests
+
+    #[test]
+    fn test_pipe_then() {
+        let count = pipe_then(vec![vec!["printf", "a\\nb\\nc\\n"]], |s| s.lines().count());
+        assert_eq!(count.unwrap(), 3);
+    }
+
+    // sh() tests
+
+    #[test]
+    fn test_sh() {
+        assert_eq!(sh("echo foo | rev").unwrap(), "oof\n");
+
+        let err = sh_with("/does/not/exist/sh", "echo foo").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::CommandNotFound);
+        assert!(err.to_string().contains("/does/not/exist/sh"));
+    }
+
+    // pipe_with_stderr_callback() tests
+
+    #[test]
+    fn test_pipe_with_stderr_callback() {
+        let mut lines = Vec::new();
+        let output = pipe_with_stderr_callback(
+            vec![vec![
+                "sh",
+                "-c",
+                "echo out1; echo err1 >&2; echo out2; echo err2 >&2; exit 3",
+            ]],
+            |line| lines.push(line.to_string()),
+        );
+        assert_eq!(output.unwrap(), ("out1\nout2\n".to_string(), 3));
+        assert_eq!(lines, vec!["err1", "err2"]);
+    }
+
+    // pipe_bytes() tests
+
+    #[test]
+    fn test_pipe_bytes() {
+        let output = pipe_bytes(vec![vec!["printf", "a\\377b"]]);
+        assert_eq!(output.unwrap(), b"a\xffb");
+    }
+
+    #[cfg(feature = "bytes")]
+    #[test]
+    fn test_pipe_bytes_shared() {
+        let output = pipe_bytes_shared(vec![vec!["echo", "foo"]]).unwrap();
+        assert_eq!(&output[..], b"foo\n");
+
+        let clone = output.clone();
+        assert_eq!(clone.as_ptr(), output.as_ptr());
+    }
+
+    // pipe_csv() tests
+
+    #[cfg(feature = "csv")]
+    #[test]
+    fn test_pipe_csv() {
+        let rows = pipe_csv(vec![vec![
+            "printf",
+            "name,desc\\nfoo,\"a, b\"\\nbar,\"line1\\nline2\"\\n",
+        ]]);
+        assert_eq!(
+            rows.unwrap(),
+            vec![
+                vec!["name", "desc"],
+                vec!["foo", "a, b"],
+                vec!["bar", "line1\nline2"],
+            ]
+        );
+    }
+
+    #[cfg(feature = "csv")]
+    #[test]
+    fn test_pipe_csv_invalid() {
+        let rows = pipe_csv(vec![vec!["printf", "a,\\377\\n"]]);
+        assert_eq!(rows.unwrap_err().kind(), ErrorKind::ParseError);
+    }
+
+    // spawn_detached() tests
+
+    #[cfg(unix)]
+    #[test]
+    fn test_spawn_detached() {
+        let pids = thread::spawn(|| spawn_detached(vec![vec!["sleep", "5"]]).unwrap())
+            .join()
+            .unwrap();
+        assert_eq!(pids.len(), 1);
+
+        let pid = pids[0] as libc::pid_t;
+        let ppid = pipe(vec![vec!["ps", "-o", "ppid=", "-p", &pid.to_string()]]);
+        unsafe {
+            // Still running, in its own session but not leading it.
+            assert_eq!(libc::kill(pid, 0), 0);
+            assert_ne!(libc::getsid(pid), libc::getsid(0));
+            assert_ne!(libc::getsid(pid), pid);
+            libc::kill(pid, libc::SIGKILL);
+        }
+        // Reparented away from this process.
+        let ppid: libc::pid_t = ppid.unwrap().trim().parse().unwrap();
+        assert_ne!(ppid, unsafe { libc::getpid() });
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_spawn_detached_pipe() {
+        let path = env::temp_dir().join(format!("shutil-detached-{}", std::process::id()));
+        let script = format!("rev > {}", path.display());
+        let pids = spawn_detached(vec![vec!["echo", "foo"], vec!["sh", "-c", &script]]).unwrap();
+        assert_eq!(pids.len(), 2);
+
+        let start = Instant::now();
+        while fs::read_to_string(&path).unwrap_or_default() != "oof\n" {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    // pipe_split_on() tests
+
+    #[test]
+    fn test_pipe_split_on() {
+        let segments = pipe_split_on(b':', vec![vec!["printf", "a:b:c"]]);
+        assert_eq!(
+            segments.unwrap(),
+            vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_pipe_split_on_trailing() {
+        let segments = pipe_split_on(0x1e, vec![vec!["printf", "a\\036\\036b\\036"]]);
+        assert_eq!(
+            segments.unwrap(),
+            vec![b"a".to_vec(), b"".to_vec(), b"b".to_vec()]
+        );
+    }
+
+    // pipe_parse() tests
+
+    #[test]
+    fn test_pipe_parse() {
+        let record = pipe_parse(vec![vec!["printf", "5\\nhello world"]], |reader| {
+            let mut len = String::new();
+            reader.read_line(&mut len)?;
+            let len: usize = len
+                .trim()
+                .parse()
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "bad length"))?;
+            let mut record = vec![0u8; len];
+            reader.read_exact(&mut record)?;
+            Ok(record)
+        });
+        assert_eq!(record.unwrap(), b"hello");
+    }
+
+    #[test]
+    fn test_pipe_parse_error() {
+        let output = pipe_parse(vec![vec!["printf", "ab"]], |reader| {
+            let mut record = [0u8; 4];
+            reader.read_exact(&mut record)
+        });
+        assert_eq!(output.unwrap_err().kind(), ErrorKind::UnknownError);
+    }
+
+    // filter() tests
+
+    #[test]
+    fn test_filter() {
+        // Re-run this test binary with stdin redirected, running only
+        // filter_child().
+        let mut child = std::process::Command::new(env::current_exe().unwrap())
+            .args(["--exact", "tests::filter_child", "--ignored", "--nocapture"])
+            .env("SHUTIL_FILTER_CHILD", "1")
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(b"foo\n").unwrap();
+        let output = child.wait_with_output().unwrap();
+
+        assert!(output.status.success());
+        assert!(String::from_utf8_lossy(&output.stdout).contains("filtered: oof\n"));
+    }
+
+    #[test]
+    #[ignore = "run by test_filter with stdin redirected"]
+    fn filter_child() {
+        if env::var_os("SHUTIL_FILTER_CHILD").is_none() {
+            return;
+        }
+        let output = filter(vec![vec!["rev"]]).unwrap();
+        println!("filtered: {}", output);
+    }
+
+    // pipe_retry() tests
+
+    #[test]
+    fn test_pipe_retry_succeeds() {
+        let path = std::env::temp_dir().join(format!("shutil-retry-{}", std::process::id()));
+        let script = format!(
+            "if [ -e {0} ]; then echo ok; else touch {0}; exit 1; fi",
+            path.display()
+        );
+        let output = pipe_retry(
+            vec![vec!["sh", "-c", &script]],
+            3,
+            Duration::from_millis(1),
+            None,
+        );
+        fs::remove_file(&path).unwrap();
+        assert_eq!(output.unwrap(), "ok\n");
+    }
+
+    #[test]
+    fn test_pipe_retry_exhausted() {
+        let output = pipe_retry(vec![vec!["false"]], 3, Duration::from_millis(1), None);
+        assert_eq!(output.unwrap_err().kind(), ErrorKind::ExecError);
+    }
+
+    #[test]
+    fn test_pipe_retry_total_deadline() {
+        let start = Instant::now();
+        let output = pipe_retry(
+            vec![vec!["false"]],
+            100,
+            Duration::from_millis(10),
+            Some(start + Duration::from_secs(2)),
+        );
+        let err = output.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimeoutError);
+        assert!(std::error::Error::source(&err).is_some());
+
+        // Sleeps of 10ms up to 640ms, 1270ms in all, fit inside the deadline,
+        // with time to spare for the attempts. The next 1280ms sleep would
+        // not, so the loop gives up after the eighth attempt rather than
+        // sleeping through the deadline.
+        assert!(err.to_string().contains("after 8 attempts"));
+    }
+
+    // pipe_observe() tests
+
+    #[test]
+    fn test_pipe_observe() {
+        let mut out = Vec::new();
+        let mut err = Vec::new();
+        let code = pipe_observe(
+            vec![vec![
+                "sh",
+                "-c",
+                "echo out1; echo err1 >&2; echo out2; echo err2 >&2; echo out3; exit 2",
+            ]],
+            |line| out.push(line.to_string()),
+            |line| err.push(line.to_string()),
+        );
+        assert_eq!(code.unwrap(), 2);
+        assert_eq!(out, vec!["out1", "out2", "out3"]);
+        assert_eq!(err, vec!["err1", "err2"]);
+    }
+
+    #[test]
+    fn test_pipe_observe_pipeline() {
+        let mut out = Vec::new();
+        let mut err = Vec::new();
+        let code = pipe_observe(
+            vec![
+                vec!["sh", "-c", "echo first >&2; printf 'b\\na\\n'"],
+                vec!["sort"],
+            ],
+            |line| out.push(line.to_string()),
+            |line| err.push(line.to_string()),
+        );
+        assert_eq!(code.unwrap(), 0);
+        assert_eq!(out, vec!["a", "b"]);
+        assert_eq!(err, vec!["first"]);
+    }
+
+    // pipe_with_rusage() tests
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_pipe_with_rusage() {
+        let (output, usage) = pipe_with_rusage(vec![
+            vec![
+                "python3",
+                "-c",
+                "b = b'x' * (64 * 1024 * 1024); print(len(b))",
+            ],
+            vec!["cat"],
+        ])
+        .unwrap();
+        assert_eq!(output, "67108864\n");
+        // Only the final stage is measured, and cat stays small.
+        assert!(usage.max_rss < 64 * 1024 * 1024);
+
+        let (output, usage) = pipe_with_rusage(vec![vec![
+            "python3",
+            "-c",
+            "b = b'x' * (64 * 1024 * 1024); print(len(b))",
+        ]])
+        .unwrap();
+        assert_eq!(output, "67108864\n");
+        assert!(usage.max_rss >= 64 * 1024 * 1024);
+        assert!(usage.max_rss < 1024 * 1024 * 1024);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_pipe_with_rusage_failure() {
+        let output = pipe_with_rusage(vec![vec!["false"]]);
+        assert_eq!(output.unwrap_err().kind(), ErrorKind::ExecError);
+    }
+
+    // source() tests
+
+    #[test]
+    fn test_source() {
+        let output = source("foo\n")
+            .pipe(vec!["rev"])
+            .pipe(vec!["tr", "a-z", "A-Z"])
+            .collect();
+        assert_eq!(
+            output.unwrap(),
+            pipe(vec![
+                vec!["echo", "foo"],
+                vec!["rev"],
+                vec!["tr", "a-z", "A-Z"]
+            ])
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_source_no_commands() {
+        assert_eq!(source("foo").collect().unwrap(), "foo");
+    }
+
+    // is_builtin() tests
+
+    #[test]
+    fn test_is_builtin() {
+        assert!(is_builtin("cd"));
+        assert!(is_builtin("export"));
+        assert!(!is_builtin("ls"));
+        assert!(!is_builtin("grep"));
+        assert!(!is_builtin("echo"));
+    }
+
+    // pipe_reader() tests
+
+    #[test]
+    fn test_pipe_reader() {
+        let mut reader = pipe_reader(vec![vec!["echo", "foo"], vec!["rev"]]).unwrap();
+        let mut buf = Vec::new();
+        io::copy(&mut reader, &mut buf).unwrap();
+        assert_eq!(buf, b"oof\n");
+    }
+
+    #[test]
+    fn test_pipe_reader_failure() {
+        let mut reader = pipe_reader(vec![vec!["sh", "-c", "echo foo; exit 1"]]).unwrap();
+        let mut buf = String::new();
+        let err = reader.read_to_string(&mut buf).unwrap_err();
+        assert_eq!(buf, "foo\n");
+        let err = err.into_inner().unwrap().downcast::<Error>().unwrap();
+        assert_eq!(err.kind(), ErrorKind::ExecError);
+        assert_eq!(err.code(), Some(1));
+    }
+
+    // pipe_or() tests
+
+    #[test]
+    fn test_pipe_or() {
+        assert_eq!(pipe_or("none", vec![vec!["echo", " foo "]]), "foo");
+        assert_eq!(pipe_or("none", vec![vec!["false"]]), "none");
+        assert_eq!(
+            pipe_or("none", vec![vec!["shutil-no-such-command"]]),
+            "none"
+        );
+    }
+
+    // which() tests
+
+    #[test]
+    fn test_which() {
+        let path = which("sh").unwrap();
+        assert!(path.ends_with("sh"));
+        assert_eq!(which("sh"), Some(path));
+        assert_eq!(which("shutil-no-such-command"), None);
+
+        clear_binary_cache();
+        assert!(which("sh").is_some());
+    }
+
+    // pipe_tail_lines() tests
+
+    #[test]
+    fn test_pipe_tail_lines() {
+        let output = pipe_tail_lines(5, vec![vec!["seq", "1", "100"]]);
+        assert_eq!(output.unwrap(), vec!["96", "97", "98", "99", "100"]);
+
+        let output = pipe_tail_lines(5, vec![vec!["seq", "1", "3"]]);
+        assert_eq!(output.unwrap(), vec!["1", "2", "3"]);
+
+        let output = pipe_tail_lines(0, vec![vec!["seq", "1", "3"]]);
+        assert!(output.unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_pipe_tail_lines_failure() {
+        let output = pipe_tail_lines(5, vec![vec!["sh", "-c", "seq 1 3; exit 1"]]);
+        assert_eq!(output.unwrap_err().kind(), ErrorKind::ExecError);
+    }
+
+    // check() tests
+
+    // Held by tests that may trigger the process-wide discard warning.
+    static DISCARD_TEST_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    #[test]
+    fn test_check() {
+        let _lock = DISCARD_TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        assert!(check(vec![vec!["echo", "foo"], vec!["rev"]]).is_ok());
+        assert_eq!(
+            check(vec![vec!["false"]]).unwrap_err().kind(),
+            ErrorKind::ExecError
+        );
+    }
+
+    #[test]
+    fn test_discard_warning() {
+        assert_eq!(discard_warning("true", 0), None);
+        let warning = discard_warning("echo foo", 4).unwrap();
+        assert!(warning.contains("discarded 4 bytes"));
+        assert!(warning.contains("`echo foo`"));
+
+        let _lock = DISCARD_TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let mut warnings = Vec::new();
+        set_discard_warning(true);
+        run_discarding_to(vec![vec!["true"]], |w| warnings.push(w.to_string())).unwrap();
+        assert!(warnings.is_empty());
+        for _ in 0..2 {
+            let commands = vec![vec!["echo", "foo"], vec!["rev"]];
+            run_discarding_to(commands, |w| warnings.push(w.to_string())).unwrap();
+        }
+        set_discard_warning(false);
+
+        // The warning is emitted once, however many times output is discarded.
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("discarded 4 bytes of output from `echo foo | rev`"));
+    }
+
+    // run_env_chain() tests
+
+    #[test]
+    fn test_run_env_chain() {
+        let vars = run_env_chain(vec![
+            ("NAME".to_string(), vec!["echo", "foo"]),
+            (
+                "GREETING".to_string(),
+                vec!["sh", "-c", "echo \"hello $NAME\""],
+            ),
+        ])
+        .unwrap();
+        assert_eq!(vars["NAME"], "foo");
+        assert_eq!(vars["GREETING"], "hello foo");
+    }
+
+    #[test]
+    fn test_run_env_chain_failure() {
+        let vars = run_env_chain(vec![
+            ("A".to_string(), vec!["false"]),
+            ("B".to_string(), vec!["echo", "b"]),
+        ]);
+        assert_eq!(vars.unwrap_err().kind(), ErrorKind::ExecError);
+    }
+
+    // require_version() tests
+
+    #[test]
+    fn test_require_version() {
+        let path = std::env::temp_dir().join(format!("shutil-version-{}", std::process::id()));
+        fs::write(&path, "echo 'tool version 2.31.4 (build 7)'\n").unwrap();
+        let script = path.to_str().unwrap();
+
+        assert_eq!(require_version("sh", script, "2.31").unwrap(), "2.31.4");
+        assert_eq!(require_version("sh", script, "2.31.4").unwrap(), "2.31.4");
+        assert_eq!(require_version("sh", script, "1.99.99").unwrap(), "2.31.4");
+
+        let err = require_version("sh", script, "2.32").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ExecError);
+        let err = require_version("sh", script, "10").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ExecError);
+        let err = require_version("sh", script, "two").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidFormatError);
+
+        fs::write(&path, "echo 'no version here' >&2\n").unwrap();
+        let err = require_version("sh", script, "1.0").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ParseError);
+
+        fs::write(&path, "echo 'tool 1.10' >&2\n").unwrap();
+        assert_eq!(require_version("sh", script, "1.9").unwrap(), "1.10");
+        fs::remove_file(&path).unwrap();
+    }
+
+    // pipe_to_file_atomic() tests
+
+    #[test]
+    fn test_pipe_to_file_atomic() {
+        let dir = std::env::temp_dir().join(format!("shutil-atomic-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("out.txt");
+        fs::write(&path, "old\n").unwrap();
+
+        let result = pipe_to_file_atomic(&path, vec![vec!["sh", "-c", "echo partial; exit 1"]]);
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::ExecError);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "old\n");
+
+        pipe_to_file_atomic(&path, vec![vec!["echo", "foo"], vec!["rev"]]).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "oof\n");
+
+        // No temporary files are left behind.
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // pipe_interactive_handle() tests
+
+    #[test]
+    fn test_pipe_interactive_handle() {
+        let (mut stdin, reader, handle) = pipe_interactive_handle(vec![vec![
+            "sh",
+            "-c",
+            "while read e; do echo $(($e)); done",
+        ]])
+        .unwrap();
+        let mut reader = BufReader::new(reader);
+        let mut line = String::new();
+
+        stdin.write_all(b"1+1\n").unwrap();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "2\n");
+
+        line.clear();
+        stdin.write_all(b"6*7\n").unwrap();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "42\n");
+
+        drop(stdin);
+        line.clear();
+        assert_eq!(reader.read_line(&mut line).unwrap(), 0);
+        assert_eq!(handle.wait().unwrap(), "");
+    }
+
+    // pipe_map_inputs() tests
+
+    #[test]
+    fn test_pipe_map_inputs() {
+        let inputs = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        let results = pipe_map_inputs(inputs, |input| {
+            vec![vec!["echo".to_string(), input.to_string()]]
+        });
+        let outputs: Vec<String> = results.into_iter().map(|r| r.unwrap()).collect();
+        assert_eq!(outputs, vec!["a\n", "b\n", "c\n"]);
+    }
+
+    #[test]
+    fn test_pipe_map_inputs_failure() {
+        let inputs = vec!["0".to_string(), "1".to_string(), "0".to_string()];
+        let results = pipe_map_inputs(inputs, |code| {
+            vec![vec![
+                "sh".to_string(),
+                "-c".to_string(),
+                format!("echo ok; exit {}", code),
+            ]]
+        });
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].as_ref().unwrap(), "ok\n");
+        assert_eq!(
+            results[1].as_ref().unwrap_err().kind(),
+            ErrorKind::ExecError
+        );
+        assert_eq!(results[2].as_ref().unwrap(), "ok\n");
+
+        assert!(pipe_map_inputs(Vec::new(), |_input| Vec::new()).is_empty());
+    }
+
+    // pipe_expect_stderr() tests
+
+    #[test]
+    fn test_pipe_expect_stderr() {
+        let commands = || vec![vec!["sh", "-c", "echo out; echo 'warning: disk low' >&2"]];
+        assert_eq!(pipe_expect_stderr("disk low", commands()).unwrap(), "out\n");
+
+        let err = pipe_expect_stderr("disk full", commands()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ExecError);
+        assert_eq!(err.stderr(), Some("warning: disk low\n"));
+    }
+
+    #[test]
+    fn test_pipe_expect_stderr_failure() {
+        let err = pipe_expect_stderr("oops", vec![vec!["sh", "-c", "echo oops >&2; exit 1"]])
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ExecError);
+        assert_eq!(err.code(), Some(1));
+        assert_eq!(err.stderr(), Some("oops\n"));
+    }
+
+    // to_script() tests
+
+    #[test]
+    fn test_to_script() {
+        let commands = || {
+            vec![
+                vec!["sh", "-c", "echo \"$GREETING, it's $0\"", "a b"],
+                vec!["tr", "a-z", "A-Z"],
+            ]
+        };
+        let env = [("GREETING", "hello world")];
+        let script = to_script(commands(), &env, None).unwrap();
+        assert_eq!(
+            script,
+            "#!/bin/sh\n\
+             export GREETING='hello world'\n\
+             sh -c 'echo \"$GREETING, it'\\''s $0\"' 'a b' | tr a-z A-Z\n"
+        );
+
+        let output = pipe(vec![vec!["sh", "-c", &script]]).unwrap();
+        assert_eq!(output, "HELLO WORLD, IT'S A B\n");
+        let direct = Pipeline::new(commands())
+            .env("GREETING", "hello world")
+            .output()
+            .unwrap();
+        assert_eq!(output, direct);
+    }
+
+    #[test]
+    fn test_to_script_cwd() {
+        let script = to_script(vec![vec!["pwd"]], &[], Some(Path::new("/"))).unwrap();
+        assert_eq!(script, "#!/bin/sh\ncd / || exit 1\npwd\n");
+        assert_eq!(pipe(vec![vec!["sh", "-c", &script]]).unwrap(), "/\n");
+    }
+
+    #[test]
+    fn test_to_script_invalid() {
+        for name in ["X=1; rm -rf ~; Y", "1X", "", "A-B"] {
+            let script = to_script(vec![vec!["true"]], &[(name, "x")], None);
+            assert_eq!(script.unwrap_err().kind(), ErrorKind::InvalidFormatError);
+        }
+        assert!(to_script(vec![vec!["true"]], &[("_A1", "x")], None).is_ok());
+
+        #[cfg(unix)]
+        {
+            use std::ffi::OsStr;
+            use std::os::unix::ffi::OsStrExt;
+
+            let cwd = Path::new(OsStr::from_bytes(b"/tmp/\xff"));
+            let script = to_script(vec![vec!["pwd"]], &[], Some(cwd));
+            assert_eq!(script.unwrap_err().kind(), ErrorKind::InvalidFormatError);
+        }
+    }
+
+    // pipe_summary() tests
+
+    #[test]
+    fn test_pipe_summary() {
+        assert_eq!(pipe_summary(vec![vec!["true"]]).unwrap(), (0, false));
+        assert_eq!(pipe_summary(vec![vec!["echo", "x"]]).unwrap(), (0, true));
+        assert_eq!(
+            pipe_summary(vec![vec!["sh", "-c", "echo x; exit 4"]]).unwrap(),
+            (4, true)
+        );
+    }
+
+    // pipe_with_fallback() tests
+
+    #[test]
+    fn test_pipe_with_fallback() {
+        let start = Instant::now();
+        let output = pipe_with_fallback(
+            vec![vec!["sh", "-c", "sleep 5; echo slow"]],
+            Duration::from_millis(200),
+            vec![vec!["echo", "fast"]],
+        );
+        assert_eq!(output.unwrap(), "fast\n");
+        assert!(start.elapsed() < Duration::from_secs(2));
+
+        let output = pipe_with_fallback(
+            vec![vec!["echo", "slow"]],
+            Duration::from_secs(5),
+            vec![vec!["echo", "fast"]],
+        );
+        assert_eq!(output.unwrap(), "slow\n");
+
+        // Errors other than a timeout do not fall back.
+        let output = pipe_with_fallback(
+            vec![vec!["false"]],
+            Duration::from_secs(5),
+            vec![vec!["echo", "fast"]],
+        );
+        assert_eq!(output.unwrap_err().kind(), ErrorKind::ExecError);
+    }
 }
@@ -0,0 +1,3703 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, PipeReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStderr, ChildStdin, Command, ExitStatus, Stdio};
+#[cfg(unix)]
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU64, Ordering};
+use std::sync::{mpsc, Mutex, MutexGuard, OnceLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::error::{Error, ErrorKind};
+
+// The most output of an earlier stage kept when a later stage fails to spawn.
+const PARTIAL_OUTPUT_LIMIT: u64 = 1024 * 1024;
+
+// How long to wait for that output, since the earlier stages may keep running.
+const PARTIAL_OUTPUT_GRACE: Duration = Duration::from_millis(100);
+
+// How often to check whether a pipeline with a deadline has exited.
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// Pipeline
+//_____________________________________________________________________________
+
+/// A configurable shell command pipeline. `Pipeline::new()` takes the same Vec
+/// of shell commands as `pipe()`, and the builder methods change how the
+/// pipeline is run before calling `output()`.
+///
+/// `pipe(commands)` is equivalent to `Pipeline::new(commands).output()`.
+#[derive(Debug)]
+pub struct Pipeline {
+    commands: Vec<Vec<String>>,
+    capture_stage: Option<usize>,
+    unbuffered: bool,
+    cache_binary_paths: bool,
+    env_map: Option<HashMap<String, String>>,
+    discard_stdout: bool,
+    pipe_stderr: bool,
+    #[cfg(target_os = "linux")]
+    ionice: Option<(IoClass, u8)>,
+    #[cfg(target_os = "linux")]
+    pipe_capacity: Option<usize>,
+    #[cfg(target_os = "linux")]
+    cgroup: Option<PathBuf>,
+    deadline: Option<Instant>,
+    stdin: Option<StdinReader>,
+    #[cfg(unix)]
+    restore_default_sigpipe: bool,
+    sudo: bool,
+    sudo_user: Option<String>,
+    ignore_stdin_write_errors: bool,
+    #[cfg(unix)]
+    detach: bool,
+    success_when: Option<SuccessPredicate>,
+    inherit_stdin: bool,
+    pipe_stdin: bool,
+    max_stages: Option<usize>,
+    max_args: Option<usize>,
+    optional_tail: Option<usize>,
+    read_timeout: Option<Duration>,
+    stage_io: HashMap<usize, StageIo>,
+    transforms: HashMap<usize, Transform>,
+    #[cfg(unix)]
+    handle_ctrl_c: bool,
+    #[cfg(unix)]
+    forward_signals: Vec<i32>,
+    envs: Vec<(String, String)>,
+    stdin_redirect: Option<Redirect>,
+    stdout_redirect: Option<Redirect>,
+    stderr_redirect: Option<Redirect>,
+    stdout_writer: Option<StdoutWriter>,
+    #[cfg(all(feature = "seccomp", target_os = "linux"))]
+    seccomp_allow: Option<Vec<i64>>,
+}
+
+/// The result of running a pipeline, passed to `Pipeline::success_when()`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ExecResult {
+    /// Exit code of the final command, or None if it was killed by a signal.
+    pub code: Option<i32>,
+    /// Captured stdout, with invalid utf-8 replaced with U+FFFD.
+    pub stdout: String,
+    /// Stderr of every stage, in pipeline order, with invalid utf-8 replaced
+    /// with U+FFFD.
+    pub stderr: String,
+}
+
+// A user supplied success condition.
+struct SuccessPredicate(Box<dyn Fn(&ExecResult) -> bool + Send>);
+
+impl fmt::Debug for SuccessPredicate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SuccessPredicate")
+    }
+}
+
+type TransformFn = dyn FnOnce(&mut dyn BufRead, &mut dyn Write) -> io::Result<()> + Send;
+
+// A closure run in-process between two stages, set by
+// `Pipeline::transform_streaming()`.
+struct Transform(Box<TransformFn>);
+
+impl fmt::Debug for Transform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Transform")
+    }
+}
+
+/// Everything about a finished pipeline that a CLI typically reports,
+/// returned by `Pipeline::outcome()`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PipelineOutcome {
+    /// Captured stdout, with invalid utf-8 replaced with U+FFFD.
+    pub stdout: String,
+    /// Stderr of every stage, in pipeline order, with invalid utf-8 replaced
+    /// with U+FFFD.
+    pub stderr: String,
+    /// Exit code of the final command.
+    pub code: i32,
+    /// Time from spawning the pipeline until every stage exited.
+    pub duration: Duration,
+    /// The command line that ran, with each argument escaped for a POSIX
+    /// shell and stages joined by ` | `.
+    pub command: String,
+}
+
+/// Where the first command reads stdin from, for `IoConfig`, or where any
+/// command reads stdin from, for `Pipeline::stage_io()`.
+pub enum StdinMode {
+    /// Read the stdout of the previous command. For the first command this is
+    /// the same as Inherit.
+    Pipe,
+    /// Read the parent's stdin.
+    Inherit,
+    /// Read from /dev/null.
+    Null,
+    /// Read from a file.
+    File(PathBuf),
+    /// Read the contents of a reader, as with `Pipeline::stdin_reader()`.
+    Reader(Box<dyn Read + Send>),
+}
+
+/// Where the final command's stdout goes, for `IoConfig`, or where any
+/// command's stdout goes, for `Pipeline::stage_io()`.
+pub enum StdoutMode {
+    /// Capture it into the result. For a command other than the last, pipe it
+    /// to the next command instead.
+    Capture,
+    /// Write to the parent's stdout.
+    Inherit,
+    /// Discard it.
+    Null,
+    /// Write to a file, which is created or truncated.
+    File(PathBuf),
+    /// Copy it into a writer as it is produced. For the whole pipeline, only
+    /// `Pipeline::exec()` copies into the writer, and other terminal methods
+    /// return a shutil::Error with kind() set to InvalidFormatError.
+    Writer(Box<dyn Write + Send>),
+}
+
+/// Where the stderr of every command goes, for `IoConfig`, or of one command,
+/// for `Pipeline::stage_io()`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum StderrMode {
+    /// Capture it into the result, combined in pipeline order.
+    Capture,
+    /// Write to the parent's stderr.
+    Inherit,
+    /// Discard it.
+    Null,
+    /// Write to a file, which is created or truncated and shared by every
+    /// command.
+    File(PathBuf),
+}
+
+/// The stdin, stdout and stderr destinations of a pipeline, set all at once
+/// with `Pipeline::io()`. The default reads the parent's stdin, captures
+/// stdout and writes stderr to the parent's stderr, which is how a pipeline
+/// runs without a config.
+#[derive(Debug)]
+pub struct IoConfig {
+    pub stdin: StdinMode,
+    pub stdout: StdoutMode,
+    pub stderr: StderrMode,
+}
+
+impl Default for IoConfig {
+    fn default() -> IoConfig {
+        IoConfig {
+            stdin: StdinMode::Inherit,
+            stdout: StdoutMode::Capture,
+            stderr: StderrMode::Inherit,
+        }
+    }
+}
+
+impl fmt::Debug for StdinMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StdinMode::Pipe => write!(f, "Pipe"),
+            StdinMode::Inherit => write!(f, "Inherit"),
+            StdinMode::Null => write!(f, "Null"),
+            StdinMode::File(path) => f.debug_tuple("File").field(path).finish(),
+            StdinMode::Reader(_) => write!(f, "Reader"),
+        }
+    }
+}
+
+impl fmt::Debug for StdoutMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StdoutMode::Capture => write!(f, "Capture"),
+            StdoutMode::Inherit => write!(f, "Inherit"),
+            StdoutMode::Null => write!(f, "Null"),
+            StdoutMode::File(path) => f.debug_tuple("File").field(path).finish(),
+            StdoutMode::Writer(_) => write!(f, "Writer"),
+        }
+    }
+}
+
+// A stream redirected away from its default by `Pipeline::io()`.
+#[derive(Debug)]
+enum Redirect {
+    Inherit,
+    Null,
+    File(PathBuf),
+}
+
+impl Redirect {
+    // Open the redirect `count` times. A file is opened once and shared, so
+    // output from every stage is written in order rather than overwritten.
+    fn open(&self, write: bool, count: usize) -> io::Result<Vec<Stdio>> {
+        let file = match self {
+            Redirect::File(path) if write => Some(File::create(path)?),
+            Redirect::File(path) => Some(OpenOptions::new().read(true).open(path)?),
+            _ => None,
+        };
+        (0..count)
+            .map(|_| match (self, &file) {
+                (_, Some(file)) => file.try_clone().map(Stdio::from),
+                (Redirect::Null, None) => Ok(Stdio::null()),
+                _ => Ok(Stdio::inherit()),
+            })
+            .collect()
+    }
+}
+
+// A writer that receives the captured stdout in `Pipeline::exec()`.
+struct StdoutWriter(Box<dyn Write + Send>);
+
+impl fmt::Debug for StdoutWriter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "StdoutWriter")
+    }
+}
+
+// The stdio of one stage, set by `Pipeline::stage_io()`.
+#[derive(Debug)]
+struct StageIo {
+    stdin: StdinMode,
+    stdout: StdoutMode,
+    stderr: StderrMode,
+}
+
+// A stage's stdio once its files are opened. None keeps the default of
+// connecting stdin or stdout to the neighbouring stages, and Err holds a
+// reader or writer that a thread copies through a pipe.
+struct OpenStageIo {
+    stdin: Option<Result<Stdio, Box<dyn Read + Send>>>,
+    stdout: Option<Result<Stdio, Box<dyn Write + Send>>>,
+    stderr: Stdio,
+}
+
+impl StageIo {
+    fn open(self) -> io::Result<OpenStageIo> {
+        let open = |path, write| Redirect::File(path).open(write, 1).map(|mut v| v.remove(0));
+        let stdin = match self.stdin {
+            StdinMode::Pipe => None,
+            StdinMode::Inherit => Some(Ok(Stdio::inherit())),
+            StdinMode::Null => Some(Ok(Stdio::null())),
+            StdinMode::File(path) => Some(Ok(open(path, false)?)),
+            StdinMode::Reader(reader) => Some(Err(reader)),
+        };
+        let stdout = match self.stdout {
+            StdoutMode::Capture => None,
+            StdoutMode::Inherit => Some(Ok(Stdio::inherit())),
+            StdoutMode::Null => Some(Ok(Stdio::null())),
+            StdoutMode::File(path) => Some(Ok(open(path, true)?)),
+            StdoutMode::Writer(writer) => Some(Err(writer)),
+        };
+        let stderr = match self.stderr {
+            StderrMode::Capture => Stdio::piped(),
+            StderrMode::Inherit => Stdio::inherit(),
+            StderrMode::Null => Stdio::null(),
+            StderrMode::File(path) => open(path, true)?,
+        };
+        Ok(OpenStageIo {
+            stdin,
+            stdout,
+            stderr,
+        })
+    }
+}
+
+// A reader whose contents are fed to the first command's stdin.
+struct StdinReader(Box<dyn Read + Send>);
+
+impl fmt::Debug for StdinReader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "StdinReader")
+    }
+}
+
+/// I/O scheduling class for `Pipeline::ionice()`, matching the classes of the
+/// `ionice` command.
+#[cfg(target_os = "linux")]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum IoClass {
+    Realtime,
+    BestEffort,
+    Idle,
+}
+
+/// Resource usage of the final command of a pipeline, as reported by
+/// `wait4()`.
+#[cfg(target_os = "linux")]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct RUsage {
+    /// Peak resident set size in bytes.
+    pub max_rss: u64,
+    /// CPU time spent in user mode.
+    pub user_time: Duration,
+    /// CPU time spent in the kernel.
+    pub system_time: Duration,
+}
+
+// A running pipeline. `stdout` reads whichever stream is being captured, which
+// is the final command's stdout unless capture_stage() selected another stage.
+// Dropping it kills and reaps any stage that has not been waited on, so an
+// early return from start() or a wait does not leave stages running.
+pub(crate) struct Spawned {
+    pub(crate) children: Vec<Child>,
+    pub(crate) stdout: Option<Box<dyn Read + Send>>,
+    pub(crate) stderr: Vec<ChildStderr>,
+    threads: Vec<JoinHandle<()>>,
+    feeders: Vec<JoinHandle<io::Result<()>>>,
+    argv: Vec<Vec<String>>,
+    read_timeout: Option<Duration>,
+    // Fields drop in order, and the forwarder is installed after the SIGINT
+    // guard, so it has to restore its handler first.
+    #[cfg(unix)]
+    forwarder: Option<SignalForwarder>,
+    #[cfg(unix)]
+    sigint: Option<SigintGuard>,
+    // The commands of a detached pipeline, which are grandchildren rather than
+    // children.
+    #[cfg(unix)]
+    detached_pids: Vec<u32>,
+}
+
+impl Pipeline {
+    pub fn new(commands: Vec<Vec<&str>>) -> Pipeline {
+        Pipeline {
+            commands: commands
+                .iter()
+                .map(|c| c.iter().map(|s| s.to_string()).collect())
+                .collect(),
+            capture_stage: None,
+            unbuffered: false,
+            cache_binary_paths: false,
+            env_map: None,
+            discard_stdout: false,
+            pipe_stderr: false,
+            #[cfg(target_os = "linux")]
+            ionice: None,
+            #[cfg(target_os = "linux")]
+            pipe_capacity: None,
+            #[cfg(target_os = "linux")]
+            cgroup: None,
+            deadline: None,
+            stdin: None,
+            #[cfg(unix)]
+            restore_default_sigpipe: false,
+            sudo: false,
+            sudo_user: None,
+            ignore_stdin_write_errors: true,
+            #[cfg(unix)]
+            detach: false,
+            success_when: None,
+            inherit_stdin: false,
+            pipe_stdin: false,
+            max_stages: None,
+            max_args: None,
+            optional_tail: None,
+            read_timeout: None,
+            stage_io: HashMap::new(),
+            transforms: HashMap::new(),
+            #[cfg(unix)]
+            handle_ctrl_c: false,
+            #[cfg(unix)]
+            forward_signals: Vec::new(),
+            envs: Vec::new(),
+            stdin_redirect: None,
+            stdout_redirect: None,
+            stderr_redirect: None,
+            stdout_writer: None,
+            #[cfg(all(feature = "seccomp", target_os = "linux"))]
+            seccomp_allow: None,
+        }
+    }
+
+    /// Capture and return the stdout of the stage at `index` instead of the
+    /// final command's stdout. The captured output is still piped into the
+    /// next stage, so the rest of the pipeline runs to completion, and the
+    /// exit status of the final command still decides success.
+    pub fn capture_stage(mut self, index: usize) -> Pipeline {
+        self.capture_stage = Some(index);
+        self
+    }
+
+    /// Force each command to line buffer its stdout by running it under
+    /// `stdbuf -oL`, so output is passed down the pipeline as each line is
+    /// written rather than when the child's stdio buffer fills.
+    ///
+    /// This depends on the `stdbuf` binary from GNU coreutils being on `PATH`,
+    /// and only affects programs that use the default C stdio buffering. If
+    /// `stdbuf` is not found, commands are run unmodified. Because `stdbuf`
+    /// execs the command, a missing binary is reported as an ExecError with
+    /// stdbuf's exit code rather than an OsError.
+    pub fn unbuffered(mut self, unbuffered: bool) -> Pipeline {
+        self.unbuffered = unbuffered;
+        self
+    }
+
+    /// Resolve each bare command name through the cache used by `which()`,
+    /// instead of searching PATH on every run. This saves the lookups when
+    /// the same pipeline runs in a tight loop. The name is still passed as
+    /// argv[0].
+    ///
+    /// A cached path that no longer exists is forgotten when spawning it
+    /// fails, so the next run searches PATH again. Other changes, such as a
+    /// binary installed earlier in PATH, are only seen after
+    /// `clear_binary_cache()`.
+    pub fn cache_binary_paths(mut self, cache: bool) -> Pipeline {
+        self.cache_binary_paths = cache;
+        self
+    }
+
+    /// Expand `$VAR` and `${VAR}` references in every command word using only
+    /// the supplied map, rather than the process environment. Variables missing
+    /// from the map expand to an empty string. A `$` that does not start a
+    /// variable reference is left as is.
+    pub fn expand_env_from(mut self, map: HashMap<String, String>) -> Pipeline {
+        self.env_map = Some(map);
+        self
+    }
+
+    /// Set the I/O scheduling class and priority level of every command, using
+    /// the `ioprio_set` syscall before the command is executed. `level` ranges
+    /// from 0 (highest) to 7 (lowest) and is ignored for the Idle class. This
+    /// pairs with CPU niceness for fully deprioritized background work.
+    #[cfg(target_os = "linux")]
+    pub fn ionice(mut self, class: IoClass, level: u8) -> Pipeline {
+        self.ionice = Some((class, level));
+        self
+    }
+
+    /// Set the capacity in bytes of the pipes connecting each stage to the next,
+    /// using `fcntl(F_SETPIPE_SZ)`. A larger buffer than the default 64KB can
+    /// improve throughput for data-heavy pipelines. The kernel rounds the size
+    /// up to a whole number of pages, and unprivileged processes are limited to
+    /// `/proc/sys/fs/pipe-max-size`; exceeding it returns an OsError.
+    #[cfg(target_os = "linux")]
+    pub fn pipe_capacity(mut self, bytes: usize) -> Pipeline {
+        self.pipe_capacity = Some(bytes);
+        self
+    }
+
+    /// Run every command in the cgroup v2 directory at `path`, so limits set
+    /// on the cgroup, such as `memory.max`, apply to them. Each command adds
+    /// itself to `cgroup.procs` after it is forked and before it is executed,
+    /// so it is in the cgroup before it does any work. Failing to join the
+    /// cgroup, for example without permission, is returned as an OsError.
+    #[cfg(target_os = "linux")]
+    pub fn cgroup(mut self, path: &Path) -> Pipeline {
+        self.cgroup = Some(path.to_path_buf());
+        self
+    }
+
+    /// Enforce an absolute deadline across the whole run of the pipeline,
+    /// covering spawning, every stage running and reading the output. If the
+    /// deadline passes at any point, every stage is killed and a shutil::Error
+    /// with kind() set to TimeoutError is returned. A deadline that has already
+    /// passed times out without spawning anything.
+    pub fn deadline(mut self, deadline: Instant) -> Pipeline {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Feed the contents of `reader` to the stdin of the first command. The
+    /// reader is copied from a separate thread, so it can be any `Read + Send`
+    /// source, such as a `File` or a `TcpStream`. Short reads and writes are
+    /// handled, and by default a command that exits before reading all of its
+    /// input does not cause an error; see `ignore_stdin_write_errors()`.
+    ///
+    /// If reading from `reader` fails, the error is returned by the terminal
+    /// method once the pipeline has exited.
+    pub fn stdin_reader<R: Read + Send + 'static>(mut self, reader: R) -> Pipeline {
+        self.stdin = Some(StdinReader(Box::new(reader)));
+        self
+    }
+
+    /// Reset SIGPIPE to its default disposition in every command before it is
+    /// executed, so a producer writing to a stage that has exited is killed by
+    /// SIGPIPE, as it would be in a shell, instead of seeing EPIPE errors. This
+    /// gives `yes | head` shell semantics regardless of how the parent process
+    /// has configured SIGPIPE.
+    ///
+    /// The standard library already resets SIGPIPE in children unless the
+    /// binary was built with `-Zon-broken-pipe=inherit`, so this guarantees the
+    /// behavior rather than changing it in the common case.
+    #[cfg(unix)]
+    pub fn restore_default_sigpipe(mut self, restore: bool) -> Pipeline {
+        self.restore_default_sigpipe = restore;
+        self
+    }
+
+    /// Kill the pipeline when the parent receives SIGINT, as when the user
+    /// presses Ctrl-C, while `output()` or another terminal method is waiting
+    /// for it, and return a shutil::Error with kind() set to Cancelled. This
+    /// keeps commands that ignore SIGINT, or are not in the terminal's
+    /// foreground process group, from running on after the user aborts.
+    ///
+    /// A SIGINT handler is installed while the pipeline runs and the previous
+    /// handler is restored once no pipeline needs it, so the parent does not
+    /// exit on SIGINT in the meantime. `PipelineHandle::wait()` is not
+    /// interrupted.
+    #[cfg(unix)]
+    pub fn handle_ctrl_c(mut self, enabled: bool) -> Pipeline {
+        self.handle_ctrl_c = enabled;
+        self
+    }
+
+    /// Forward each of `signals` received by the parent to the pipeline while
+    /// it runs, so a wrapper that is asked to stop with SIGTERM or SIGINT can
+    /// let the pipeline shut down instead of dying and leaving it orphaned.
+    /// The handlers stay installed until the terminal method returns, or the
+    /// `PipelineHandle` is dropped, and the previous handlers are then
+    /// restored.
+    ///
+    /// Every command is placed in a new process group and each signal is sent
+    /// to the whole group. Detached commands already lead their own sessions,
+    /// so the signal is sent to each of them instead. Note that commands
+    /// outside the terminal's foreground process group do not receive Ctrl-C
+    /// from the terminal directly, and stop if they read from it.
+    ///
+    /// A signal number outside 1 to 64 returns a shutil::Error with kind() set
+    /// to InvalidFormatError, and one that cannot be caught, such as SIGKILL,
+    /// returns an OsError.
+    #[cfg(unix)]
+    pub fn forward_signals(mut self, signals: &[i32]) -> Pipeline {
+        self.forward_signals = signals.to_vec();
+        self
+    }
+
+    /// Run every command with `sudo`. If `sudo` is not found on PATH, the
+    /// pipeline returns a shutil::Error with kind() set to CommandNotFound
+    /// without spawning anything. sudo may prompt for a password on the
+    /// terminal unless it is configured to allow the commands without one.
+    pub fn sudo(mut self, sudo: bool) -> Pipeline {
+        self.sudo = sudo;
+        self
+    }
+
+    /// Run every command with `sudo -u name`, as the given user. This implies
+    /// `sudo(true)`.
+    pub fn sudo_user(mut self, name: &str) -> Pipeline {
+        self.sudo = true;
+        self.sudo_user = Some(name.to_string());
+        self
+    }
+
+    /// Control whether the broken pipe from writing the `stdin_reader()` input
+    /// to the first command, after it exited or closed stdin before reading
+    /// everything, is ignored. The default is true, matching the shell, so
+    /// `big_input | head` succeeds. When false, the broken pipe is returned as
+    /// an OsError once the pipeline has exited. Other write errors are always
+    /// returned.
+    pub fn ignore_stdin_write_errors(mut self, ignore: bool) -> Pipeline {
+        self.ignore_stdin_write_errors = ignore;
+        self
+    }
+
+    // Send the final command's stdout to /dev/null instead of capturing it.
+    pub(crate) fn discard_stdout(mut self) -> Pipeline {
+        self.discard_stdout = true;
+        self
+    }
+
+    // Start every stage in a new session with stdio redirected to /dev/null,
+    // so the pipeline is not tied to the parent's terminal or lifetime.
+    #[cfg(unix)]
+    pub(crate) fn detach(mut self) -> Pipeline {
+        self.detach = true;
+        self.discard_stdout = true;
+        self
+    }
+
+    /// Decide whether the pipeline succeeded by calling `f` with its result,
+    /// instead of checking the exit status of the final command. The stderr of
+    /// every stage is captured into the result rather than inherited. If `f`
+    /// returns false, the terminal methods return a shutil::Error with kind()
+    /// set to ExecError, code() set to the exit code and the captured stderr
+    /// attached.
+    pub fn success_when<F>(mut self, f: F) -> Pipeline
+    where
+        F: Fn(&ExecResult) -> bool + Send + 'static,
+    {
+        self.success_when = Some(SuccessPredicate(Box::new(f)));
+        self
+    }
+
+    /// Run every command under a seccomp-bpf filter that only allows the
+    /// system calls numbered in `syscalls`, such as `libc::SYS_read`, plus the
+    /// `execve`, `exit` and `exit_group` calls needed to start and stop the
+    /// command. Any other system call kills the command with SIGSYS, as does
+    /// running on a different architecture ABI.
+    ///
+    /// The filter is installed just before the command is executed, after
+    /// setting no_new_privs, and is inherited by anything the command runs. A
+    /// dynamically linked command needs the calls made by its loader, such as
+    /// `mmap` and `openat`, to be allowed as well.
+    ///
+    /// This is only available with the `seccomp` feature on Linux.
+    #[cfg(all(feature = "seccomp", target_os = "linux"))]
+    pub fn seccomp_allow(mut self, syscalls: &[i64]) -> Pipeline {
+        self.seccomp_allow = Some(syscalls.to_vec());
+        self
+    }
+
+    // Set an environment variable for every command.
+    pub(crate) fn env(mut self, key: &str, value: &str) -> Pipeline {
+        self.envs.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    /// Limit the pipeline to at most `n` stages. A pipeline with more stages is
+    /// rejected with a shutil::Error with kind() set to InvalidFormatError
+    /// before anything is spawned. This guards services that run pipelines
+    /// built from untrusted input against exhausting resources.
+    pub fn max_stages(mut self, n: usize) -> Pipeline {
+        self.max_stages = Some(n);
+        self
+    }
+
+    /// Limit every command to at most `n` arguments, not counting the binary.
+    /// A command with more arguments is rejected with a shutil::Error with
+    /// kind() set to InvalidFormatError before anything is spawned.
+    pub fn max_args(mut self, n: usize) -> Pipeline {
+        self.max_args = Some(n);
+        self
+    }
+
+    /// Limit how long any single read of the captured stdout may wait for
+    /// data. The limit restarts each time data arrives, so a producer that
+    /// stalls mid-stream for longer than `timeout` has every stage killed and
+    /// a shutil::Error with kind() set to TimeoutError returned, however long
+    /// the pipeline has run in total. This guards servers against slow-trickle
+    /// producers, and can be combined with `deadline()` to bound the total.
+    ///
+    /// The limit applies to every terminal method that reads stdout, including
+    /// `to_writer()`, `to_writer_timeout()` and a `StdoutMode::Writer` passed
+    /// to `exec()`.
+    pub fn read_timeout(mut self, timeout: Duration) -> Pipeline {
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    /// Treat the stages from `index` onwards as optional. If one of them
+    /// cannot be spawned because its binary is not found, the pipeline ends at
+    /// the stage before it: that stage's output is captured and its exit
+    /// status decides success, instead of returning an error. This lets a
+    /// trailing formatter, for example, be skipped where it is not installed.
+    ///
+    /// The first stage is never optional, since there would be no output.
+    pub fn optional_tail(mut self, index: usize) -> Pipeline {
+        self.optional_tail = Some(index);
+        self
+    }
+
+    /// Set where stdin, stdout and stderr go in one declarative config,
+    /// replacing any earlier `stdin_reader()`. See `IoConfig` for the
+    /// defaults.
+    pub fn io(mut self, config: IoConfig) -> Pipeline {
+        self.stdin = None;
+        self.stdin_redirect = match config.stdin {
+            StdinMode::Pipe | StdinMode::Inherit => Some(Redirect::Inherit),
+            StdinMode::Null => Some(Redirect::Null),
+            StdinMode::File(path) => Some(Redirect::File(path)),
+            StdinMode::Reader(reader) => {
+                self.stdin = Some(StdinReader(reader));
+                None
+            }
+        };
+        self.stdout_redirect = None;
+        self.stdout_writer = None;
+        match config.stdout {
+            StdoutMode::Capture => {}
+            StdoutMode::Inherit => self.stdout_redirect = Some(Redirect::Inherit),
+            StdoutMode::Null => self.stdout_redirect = Some(Redirect::Null),
+            StdoutMode::File(path) => self.stdout_redirect = Some(Redirect::File(path)),
+            StdoutMode::Writer(writer) => self.stdout_writer = Some(StdoutWriter(writer)),
+        }
+        self.pipe_stderr = config.stderr == StderrMode::Capture;
+        self.stderr_redirect = match config.stderr {
+            StderrMode::Capture => None,
+            StderrMode::Inherit => Some(Redirect::Inherit),
+            StderrMode::Null => Some(Redirect::Null),
+            StderrMode::File(path) => Some(Redirect::File(path)),
+        };
+        self
+    }
+
+    /// Run `transform` in-process between the stage at `index` and the next
+    /// one. It reads the stdout of the stage through a buffered reader and
+    /// writes what the next stage reads, from a background thread, so a Rust
+    /// transform can stream between two commands without holding the whole
+    /// stream in memory. The next stage sees end of file once the closure
+    /// returns.
+    ///
+    /// Both sides are buffered, so the closure can work on borrowed slices,
+    /// for example with `fill_buf()` or `read_until()` into a reused buffer,
+    /// instead of allocating per call. A write failing because the next stage
+    /// stopped reading is ignored, and any other error is returned as with a
+    /// `stdin_reader()` error once the pipeline has exited.
+    ///
+    /// If the stage at `index` is also selected with `capture_stage()`, its
+    /// output is captured as it was before the transform.
+    ///
+    /// An index without a following stage returns a shutil::Error with kind()
+    /// set to InvalidFormatError when the pipeline is run.
+    pub fn transform_streaming<F>(mut self, index: usize, transform: F) -> Pipeline
+    where
+        F: FnOnce(&mut dyn BufRead, &mut dyn Write) -> io::Result<()> + Send + 'static,
+    {
+        self.transforms
+            .insert(index, Transform(Box::new(transform)));
+        self
+    }
+
+    /// Set where stdin, stdout and stderr go for the stage at `index` alone,
+    /// overriding the pipeline-wide settings for that stage. Use
+    /// `StdinMode::Pipe` and `StdoutMode::Capture` to keep a stream connected
+    /// to the neighbouring stages.
+    ///
+    /// When a stage's stdout goes elsewhere, the next stage reads /dev/null
+    /// unless its stdin is also set, and when a stage's stdin comes from
+    /// elsewhere, the previous stage's stdout is closed. A `Writer` receives
+    /// output from a background thread as it is produced, and stderr set to
+    /// `Capture` is included in the captured stderr.
+    ///
+    /// An index out of range returns a shutil::Error with kind() set to
+    /// InvalidFormatError when the pipeline is run.
+    pub fn stage_io(
+        mut self,
+        index: usize,
+        stdin: StdinMode,
+        stdout: StdoutMode,
+        stderr: StderrMode,
+    ) -> Pipeline {
+        self.stage_io.insert(
+            index,
+            StageIo {
+                stdin,
+                stdout,
+                stderr,
+            },
+        );
+        self
+    }
+
+    // Connect the first command's stdin to the parent's stdin.
+    pub(crate) fn inherit_stdin(mut self) -> Pipeline {
+        self.inherit_stdin = true;
+        self
+    }
+
+    // Pipe the first command's stdin, leaving the write end in the spawned
+    // child.
+    pub(crate) fn pipe_stdin(mut self) -> Pipeline {
+        self.pipe_stdin = true;
+        self
+    }
+
+    // Pipe the stderr of every stage to the parent, instead of inheriting it.
+    pub(crate) fn pipe_stderr(mut self) -> Pipeline {
+        self.pipe_stderr = true;
+        self
+    }
+
+    /// Execute the pipeline and return the captured stdout, with the same
+    /// error handling as `pipe()`.
+    pub fn output(mut self) -> Result<String, Error> {
+        decode_utf8(self.output_bytes()?)
+    }
+
+    /// Execute the pipeline and copy the captured stdout into `writer` as it is
+    /// produced, rather than buffering it. Together with `stdin_reader()`, this
+    /// can pipe a socket through a command and back to a socket:
+    ///
+    /// ```no_run
+    /// use std::net::TcpListener;
+    /// use shutil::Pipeline;
+    ///
+    /// let listener = TcpListener::bind("127.0.0.1:7878").unwrap();
+    /// let (mut stream, _addr) = listener.accept().unwrap();
+    /// Pipeline::new(vec![vec!["rev"]])
+    ///     .stdin_reader(stream.try_clone().unwrap())
+    ///     .to_writer(&mut stream)
+    ///     .unwrap();
+    /// ```
+    ///
+    /// Errors are returned as with `output()`, including the deadline passing,
+    /// with the output copied so far left in `writer`. Writing to `writer`
+    /// failing is returned as an OsError, or UnknownError if there is no raw os
+    /// error.
+    pub fn to_writer<W: Write>(mut self, writer: &mut W) -> Result<(), Error> {
+        check_deadline(self.deadline)?;
+        let mut spawned = self.start()?;
+        spawned.copy_stdout(writer, self.deadline)?;
+        check_status(spawned.wait_until(self.deadline)?)
+    }
+
+    /// Execute the pipeline and copy the captured stdout into `writer` as it is
+    /// produced, as with `to_writer()`, but give up if the pipeline is still
+    /// running after `timeout`. On timeout every command is killed and a
+    /// shutil::Error with kind() set to TimeoutError is returned. Output copied
+    /// before the timeout is left in `writer`.
+    ///
+    /// Other errors are returned as with `to_writer()`.
+    pub fn to_writer_timeout<W: Write>(
+        mut self,
+        writer: &mut W,
+        timeout: Duration,
+    ) -> Result<(), Error> {
+        let mut deadline = Instant::now() + timeout;
+        if let Some(d) = self.deadline {
+            deadline = deadline.min(d);
+        }
+        check_deadline(Some(deadline))?;
+        let mut spawned = self.start()?;
+        spawned.copy_stdout(writer, Some(deadline))?;
+        check_status(spawned.wait_until(Some(deadline))?)
+    }
+
+    /// Execute the pipeline and return its exit code, stdout and stderr as an
+    /// ExecResult. Stdout and stderr are only captured into the result when
+    /// `io()` selects `Capture` for them, and are empty otherwise. A non-zero
+    /// exit code is returned as part of the result rather than as an error.
+    ///
+    /// Errors running the pipeline, such as a command not being found or the
+    /// deadline passing, are returned as with `output()`.
+    pub fn exec(mut self) -> Result<ExecResult, Error> {
+        check_deadline(self.deadline)?;
+        // Taken before starting, since only exec() supports a writer.
+        let writer = self.stdout_writer.take();
+        let mut spawned = self.start()?;
+        let stderr = spawned.read_stderr();
+        let stdout = match (writer, spawned.stdout.take()) {
+            (Some(StdoutWriter(mut writer)), reader) => {
+                spawned.stdout = reader;
+                spawned.copy_stdout(&mut writer, self.deadline)?;
+                Vec::new()
+            }
+            (_, reader) => {
+                spawned.stdout = reader;
+                spawned.read_to_end(self.deadline)?
+            }
+        };
+        let status = spawned.wait_until(self.deadline)?;
+        Ok(ExecResult {
+            code: status.code(),
+            stdout: String::from_utf8_lossy(&stdout).into_owned(),
+            stderr: stderr
+                .map(|t| t.join().unwrap_or_default())
+                .unwrap_or_default(),
+        })
+    }
+
+    /// Execute the pipeline and return a PipelineOutcome with its stdout,
+    /// stderr, exit code, duration and command line. The stderr of every stage
+    /// is captured rather than inherited. A non-zero exit code is returned as
+    /// part of the outcome rather than as an error.
+    ///
+    /// If the final command is killed by a signal, a shutil::Error with kind()
+    /// set to ExecError and code() set to None is returned. Other errors are
+    /// returned as with `output()`.
+    pub fn outcome(mut self) -> Result<PipelineOutcome, Error> {
+        check_deadline(self.deadline)?;
+        self.pipe_stderr = true;
+        let start = Instant::now();
+        let mut spawned = self.start()?;
+        let command = spawned
+            .argv
+            .iter()
+            .map(|argv| {
+                argv.iter()
+                    .map(|word| shell_escape(word))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            })
+            .collect::<Vec<_>>()
+            .join(" | ");
+        let stderr = spawned.read_stderr();
+        let stdout = spawned.read_to_end(self.deadline)?;
+        let code = exit_code(spawned.wait_until(self.deadline)?)?;
+        Ok(PipelineOutcome {
+            stdout: String::from_utf8_lossy(&stdout).into_owned(),
+            stderr: stderr
+                .map(|t| t.join().unwrap_or_default())
+                .unwrap_or_default(),
+            code,
+            duration: start.elapsed(),
+            command,
+        })
+    }
+
+    /// Spawn the pipeline without waiting for it, returning a handle that can
+    /// be used to monitor the running commands and later collect the output.
+    ///
+    /// Errors spawning the pipeline are returned as with `output()`.
+    pub fn spawn(mut self) -> Result<PipelineHandle, Error> {
+        check_deadline(self.deadline)?;
+        Ok(PipelineHandle {
+            spawned: Some(self.start()?),
+            deadline: self.deadline,
+        })
+    }
+
+    // Execute the pipeline and return the raw captured stdout.
+    pub(crate) fn output_bytes(&mut self) -> Result<Vec<u8>, Error> {
+        self.run().map(|(stdout, _argv)| stdout)
+    }
+
+    /// Execute the pipeline and return the captured stdout along with the exact
+    /// argument vector passed to each command, after variable expansion and
+    /// any `stdbuf` wrapping, for audit logs of what actually ran.
+    ///
+    /// Errors are returned as with `output()`.
+    pub fn output_with_argv(mut self) -> Result<(String, Vec<Vec<String>>), Error> {
+        let (stdout, argv) = self.run()?;
+        Ok((decode_utf8(stdout)?, argv))
+    }
+
+    // Execute the pipeline and return the raw captured stdout and the argv of
+    // each command.
+    fn run(&mut self) -> Result<(Vec<u8>, Vec<Vec<String>>), Error> {
+        check_deadline(self.deadline)?;
+        let mut spawned = self.start()?;
+        let argv = std::mem::take(&mut spawned.argv);
+        let stderr = spawned.read_stderr();
+        let stdout = spawned.read_to_end(self.deadline)?;
+        let status = spawned.wait_until(self.deadline)?;
+
+        match &self.success_when {
+            Some(SuccessPredicate(pred)) => {
+                let result = ExecResult {
+                    code: status.code(),
+                    stdout: String::from_utf8_lossy(&stdout).into_owned(),
+                    stderr: stderr
+                        .map(|t| t.join().unwrap_or_default())
+                        .unwrap_or_default(),
+                };
+                if !pred(&result) {
+                    return Err(Error::new(
+                        ErrorKind::ExecError,
+                        result.code,
+                        "success condition not met",
+                    )
+                    .with_stderr(result.stderr));
+                }
+            }
+            None => check_status(status)?,
+        }
+        Ok((stdout, argv))
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        if self.commands.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidFormatError,
+                Some(-1),
+                "no commands supplied",
+            ));
+        }
+
+        let empty = self
+            .commands
+            .iter()
+            .position(|c| c.first().is_none_or(|binary| binary.is_empty()));
+        if let Some(index) = empty {
+            return Err(Error::new(
+                ErrorKind::InvalidFormatError,
+                Some(-1),
+                format!("stage {}: no command binary supplied", index).as_str(),
+            ));
+        }
+
+        if self.stdout_writer.is_some() {
+            return Err(Error::new(
+                ErrorKind::InvalidFormatError,
+                Some(-1),
+                "StdoutMode::Writer is only supported by exec()",
+            ));
+        }
+
+        #[cfg(unix)]
+        if let Some(signal) = self
+            .forward_signals
+            .iter()
+            .find(|&&s| !(1..=64).contains(&s))
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidFormatError,
+                Some(-1),
+                format!("signal {} cannot be forwarded", signal).as_str(),
+            ));
+        }
+
+        if let Some(max) = self.max_stages {
+            if self.commands.len() > max {
+                return Err(Error::new(
+                    ErrorKind::InvalidFormatError,
+                    Some(-1),
+                    format!("{} stages exceed the limit of {}", self.commands.len(), max).as_str(),
+                ));
+            }
+        }
+
+        if let Some(max) = self.max_args {
+            let long = self.commands.iter().position(|c| c.len() - 1 > max);
+            if let Some(index) = long {
+                return Err(Error::new(
+                    ErrorKind::InvalidFormatError,
+                    Some(-1),
+                    format!(
+                        "stage {}: {} arguments exceed the limit of {}",
+                        index,
+                        self.commands[index].len() - 1,
+                        max
+                    )
+                    .as_str(),
+                ));
+            }
+        }
+
+        if let Some(index) = self
+            .transforms
+            .keys()
+            .find(|&&i| i + 1 >= self.commands.len())
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidFormatError,
+                Some(-1),
+                format!("transform stage {} has no following stage", index).as_str(),
+            ));
+        }
+
+        if let Some(index) = self.stage_io.keys().find(|&&i| i >= self.commands.len()) {
+            return Err(Error::new(
+                ErrorKind::InvalidFormatError,
+                Some(-1),
+                format!("stage_io stage {} out of range", index).as_str(),
+            ));
+        }
+
+        if let Some(index) = self.capture_stage {
+            if index >= self.commands.len() {
+                return Err(Error::new(
+                    ErrorKind::InvalidFormatError,
+                    Some(-1),
+                    format!("capture stage {} out of range", index).as_str(),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Return the words of the stage at `index` after variable expansion.
+    fn argv(&self, index: usize) -> Vec<String> {
+        match &self.env_map {
+            Some(map) => self.commands[index]
+                .iter()
+                .map(|word| expand_vars(word, map))
+                .collect(),
+            None => self.commands[index].clone(),
+        }
+    }
+
+    #[cfg(unix)]
+    fn is_detached(&self) -> bool {
+        self.detach
+    }
+
+    #[cfg(not(unix))]
+    fn is_detached(&self) -> bool {
+        false
+    }
+
+    // Create the command for `binary`. With cache_binary_paths() set, a bare
+    // name is resolved through the binary cache, with PATH as the command will
+    // see it, and kept as argv[0]. Otherwise, or if it is not found, the name
+    // is left for the OS to search for and report.
+    fn command(&self, binary: &str) -> Command {
+        #[cfg(unix)]
+        if self.cache_binary_paths && !binary.contains('/') {
+            use std::os::unix::process::CommandExt;
+
+            if let Some(path) = self.path_var().and_then(|paths| find_in(binary, paths)) {
+                let mut command = Command::new(path);
+                command.arg0(binary);
+                return command;
+            }
+        }
+        Command::new(binary)
+    }
+
+    // The PATH the commands will see.
+    fn path_var(&self) -> Option<OsString> {
+        match self.envs.iter().rev().find(|(k, _v)| k == "PATH") {
+            Some((_k, v)) => Some(OsString::from(v)),
+            None => env::var_os("PATH"),
+        }
+    }
+
+    // Apply the per-process options to a command before it is spawned.
+    #[allow(unused_variables)]
+    fn configure(&self, command: &mut Command) {
+        command.envs(self.envs.iter().map(|(k, v)| (k, v)));
+
+        #[cfg(target_os = "linux")]
+        if let Some((class, level)) = self.ionice {
+            use std::os::unix::process::CommandExt;
+
+            const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+            const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+            let class = match class {
+                IoClass::Realtime => 1,
+                IoClass::BestEffort => 2,
+                IoClass::Idle => 3,
+            };
+            let ioprio = (class << IOPRIO_CLASS_SHIFT) | libc::c_int::from(level.min(7));
+            unsafe {
+                command.pre_exec(move || {
+                    if libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ioprio) < 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                    Ok(())
+                });
+            }
+        }
+
+        #[cfg(unix)]
+        if self.detach {
+            use std::os::unix::process::CommandExt;
+
+            unsafe {
+                command.pre_exec(|| {
+                    if libc::setsid() < 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                    Ok(())
+                });
+            }
+        }
+
+        #[cfg(unix)]
+        if self.restore_default_sigpipe {
+            use std::os::unix::process::CommandExt;
+
+            unsafe {
+                command.pre_exec(|| {
+                    if libc::signal(libc::SIGPIPE, libc::SIG_DFL) == libc::SIG_ERR {
+                        return Err(io::Error::last_os_error());
+                    }
+                    Ok(())
+                });
+            }
+        }
+
+        #[cfg(target_os = "linux")]
+        if let Some(path) = &self.cgroup {
+            use std::os::unix::ffi::OsStrExt;
+            use std::os::unix::process::CommandExt;
+
+            // The path is converted before forking, since allocating in the
+            // child is not async-signal-safe.
+            let procs = std::ffi::CString::new(path.join("cgroup.procs").as_os_str().as_bytes());
+            unsafe {
+                command.pre_exec(move || {
+                    let procs = match &procs {
+                        Ok(procs) => procs,
+                        Err(_e) => return Err(io::ErrorKind::InvalidInput.into()),
+                    };
+                    // Writing 0 moves the calling process.
+                    let fd = libc::open(procs.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+                    if fd < 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                    let written = libc::write(fd, b"0".as_ptr() as *const libc::c_void, 1);
+                    let err = io::Error::last_os_error();
+                    libc::close(fd);
+                    if written < 0 {
+                        return Err(err);
+                    }
+                    Ok(())
+                });
+            }
+        }
+
+        // The filter is installed last, so the other hooks can make calls it
+        // does not allow.
+        #[cfg(all(feature = "seccomp", target_os = "linux"))]
+        if let Some(syscalls) = &self.seccomp_allow {
+            use std::os::unix::process::CommandExt;
+
+            let filter = seccomp_filter(syscalls);
+            unsafe {
+                command.pre_exec(move || {
+                    let filter = match &filter {
+                        Some(filter) => filter,
+                        None => return Err(io::ErrorKind::Unsupported.into()),
+                    };
+                    let prog = libc::sock_fprog {
+                        len: filter.len() as libc::c_ushort,
+                        filter: filter.as_ptr() as *mut libc::sock_filter,
+                    };
+                    if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) < 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                    if libc::prctl(
+                        libc::PR_SET_SECCOMP,
+                        libc::SECCOMP_MODE_FILTER,
+                        &prog as *const libc::sock_fprog,
+                    ) < 0
+                    {
+                        return Err(io::Error::last_os_error());
+                    }
+                    Ok(())
+                });
+            }
+        }
+    }
+
+    // Spawn every stage, connecting the stdout of each command to the stdin of
+    // the next. If any step fails, dropping `spawned` kills the stages that
+    // were already started, so every error can simply be returned.
+    pub(crate) fn start(&mut self) -> Result<Spawned, Error> {
+        self.validate()?;
+
+        let last = self.commands.len() - 1;
+        let capture = self.capture_stage.unwrap_or(last);
+        // A captured stage with a transform after it is teed before the
+        // transform, so the capture holds the stage's own output.
+        let capture_before_transform = self.transforms.contains_key(&capture);
+        let mut spawned = Spawned {
+            children: Vec::new(),
+            stdout: None,
+            stderr: Vec::new(),
+            threads: Vec::new(),
+            feeders: Vec::new(),
+            argv: Vec::new(),
+            read_timeout: self.read_timeout,
+            #[cfg(unix)]
+            forwarder: None,
+            #[cfg(unix)]
+            sigint: None,
+            #[cfg(unix)]
+            detached_pids: Vec::new(),
+        };
+        #[cfg(unix)]
+        if self.handle_ctrl_c {
+            spawned.sigint = Some(SigintGuard::install().map_err(Error::from_io)?);
+        }
+        #[cfg(unix)]
+        if !self.forward_signals.is_empty() {
+            let forwarder = SignalForwarder::install(&self.forward_signals);
+            spawned.forwarder = Some(forwarder.map_err(Error::from_io)?);
+        }
+        let mut prev_stdout: Option<PipeReader> = None;
+        let stdbuf = if self.unbuffered {
+            find_in_path("stdbuf")
+        } else {
+            None
+        };
+        let sudo = if self.sudo {
+            match find_in_path("sudo") {
+                Some(path) => Some(path),
+                None => {
+                    return Err(Error::new(
+                        ErrorKind::CommandNotFound,
+                        None,
+                        "sudo not found",
+                    ));
+                }
+            }
+        } else {
+            None
+        };
+
+        let open = |redirect: &Option<Redirect>, write, count| match redirect {
+            Some(redirect) => redirect.open(write, count).map_err(Error::from_io),
+            None => Ok(Vec::new()),
+        };
+        let mut stdin_redirect = open(&self.stdin_redirect, false, 1)?.pop();
+        let mut stdout_redirect = open(&self.stdout_redirect, true, 1)?.pop();
+        let mut stderr_redirects = open(&self.stderr_redirect, true, self.commands.len())?;
+        let mut stage_io = Vec::new();
+        for i in 0..self.commands.len() {
+            stage_io.push(match self.stage_io.remove(&i) {
+                Some(stage) => Some(stage.open().map_err(Error::from_io)?),
+                None => None,
+            });
+        }
+        let mut stage_io = stage_io.into_iter();
+
+        for i in 0..self.commands.len() {
+            let mut argv = self.argv(i);
+            if let Some(path) = &stdbuf {
+                let prefix = [path.to_string_lossy().into_owned(), "-oL".to_string()];
+                argv.splice(0..0, prefix);
+            }
+            // sudo resets the environment, so it has to wrap stdbuf.
+            if let Some(path) = &sudo {
+                let mut prefix = vec![path.to_string_lossy().into_owned()];
+                if let Some(user) = &self.sudo_user {
+                    prefix.extend(["-u".to_string(), user.clone()]);
+                }
+                argv.splice(0..0, prefix);
+            }
+            let mut command = self.command(&argv[0]);
+            command.args(&argv[1..]);
+            spawned.argv.push(argv);
+
+            self.configure(&mut command);
+
+            let stdin_reader = match i {
+                0 => self.stdin.take(),
+                _ => None,
+            };
+            if stdin_reader.is_some() {
+                command.stdin(Stdio::piped());
+            } else if let Some(stdin) = stdin_redirect.take() {
+                command.stdin(stdin);
+            } else if i == 0 && self.is_detached() {
+                command.stdin(Stdio::null());
+            } else if i == 0 && self.inherit_stdin {
+                command.stdin(Stdio::inherit());
+            } else if i == 0 && self.pipe_stdin {
+                command.stdin(Stdio::piped());
+            }
+
+            let (stage_stdin, stage_stdout, stage_stderr) = match stage_io.next().flatten() {
+                Some(stage) => (stage.stdin, stage.stdout, Some(stage.stderr)),
+                None => (None, None, None),
+            };
+            let stdin_overridden = stage_stdin.is_some();
+            let mut stage_reader = None;
+            match stage_stdin {
+                Some(Ok(stdin)) => {
+                    command.stdin(stdin);
+                }
+                Some(Err(reader)) => {
+                    command.stdin(Stdio::piped());
+                    stage_reader = Some(reader);
+                }
+                None => {}
+            }
+
+            // The captured stage's output is copied by a thread, so the next
+            // stage reads from a pipe fed by that thread rather than directly.
+            // Otherwise keep a second handle to the input, so the previous
+            // stage's output can be recovered if this stage fails to spawn.
+            let mut input = None;
+            let mut tee_input = None;
+            match prev_stdout.take() {
+                Some(reader) if i == capture + 1 && !capture_before_transform => {
+                    if !stdin_overridden {
+                        command.stdin(Stdio::piped());
+                    }
+                    tee_input = Some(reader);
+                }
+                Some(reader) if stdin_overridden => drop(reader),
+                Some(reader) => {
+                    input = reader.try_clone().ok();
+                    command.stdin(reader);
+                }
+                None if i > 0 && !stdin_overridden => {
+                    command.stdin(Stdio::null());
+                }
+                None => {}
+            }
+
+            let mut next_stdout = None;
+            if let Some(stdout) = stage_stdout {
+                match stdout {
+                    Ok(stdout) => {
+                        command.stdout(stdout);
+                    }
+                    Err(mut output) => {
+                        let (mut reader, writer) = io::pipe().map_err(Error::from_io)?;
+                        command.stdout(writer);
+                        spawned.threads.push(thread::spawn(move || {
+                            let _ = io::copy(&mut reader, &mut output).and_then(|_| output.flush());
+                        }));
+                    }
+                }
+            } else if i < last {
+                let (reader, writer) = io::pipe().map_err(Error::from_io)?;
+                #[cfg(target_os = "linux")]
+                if let Some(bytes) = self.pipe_capacity {
+                    set_pipe_capacity(&reader, bytes)?;
+                }
+                command.stdout(writer);
+                next_stdout = Some(match self.transforms.remove(&i) {
+                    Some(Transform(transform)) => {
+                        let reader = if i == capture {
+                            let (raw, raw_writer) = io::pipe().map_err(Error::from_io)?;
+                            let (captured, capture_writer) = io::pipe().map_err(Error::from_io)?;
+                            spawned.threads.push(thread::spawn(move || {
+                                tee(reader, Some(raw_writer), capture_writer)
+                            }));
+                            spawned.stdout = Some(Box::new(captured));
+                            raw
+                        } else {
+                            reader
+                        };
+                        let (transformed, output) = io::pipe().map_err(Error::from_io)?;
+                        spawned.feeders.push(thread::spawn(move || {
+                            run_transform(transform, reader, output)
+                        }));
+                        transformed
+                    }
+                    None => reader,
+                });
+            } else if let Some(stdout) = stdout_redirect.take() {
+                command.stdout(stdout);
+            } else if capture != last || self.discard_stdout {
+                command.stdout(Stdio::null());
+            } else {
+                command.stdout(Stdio::piped());
+            }
+
+            let stderr_redirect = stderr_redirects.pop();
+            if let Some(stderr) = stage_stderr {
+                command.stderr(stderr);
+            } else if self.pipe_stderr || self.success_when.is_some() {
+                command.stderr(Stdio::piped());
+            } else if let Some(stderr) = stderr_redirect {
+                command.stderr(stderr);
+            } else if self.is_detached() {
+                command.stderr(Stdio::null());
+            }
+
+            #[cfg(unix)]
+            if spawned.forwarder.is_some() && !self.is_detached() {
+                use std::os::unix::process::CommandExt;
+
+                match spawned.children.first() {
+                    Some(leader) => command.process_group(leader.id() as i32),
+                    None => command.process_group(0),
+                };
+            }
+
+            // A detached command is forked again once it has its own session,
+            // and the intermediate process reports the pid of the command on
+            // this pipe before exiting.
+            #[cfg(unix)]
+            let pid_pipe = match self.is_detached() {
+                true => {
+                    let (reader, writer) = io::pipe().map_err(Error::from_io)?;
+                    double_fork(&mut command, &writer);
+                    Some((reader, writer))
+                }
+                false => None,
+            };
+
+            let spawn_result = command.spawn();
+            // Close the parent's copies of the pipe ends given to the child.
+            drop(command);
+            if let Err(e) = &spawn_result {
+                if self.cache_binary_paths && e.kind() == io::ErrorKind::NotFound {
+                    if let Some(paths) = self.path_var() {
+                        forget_binary(&spawned.argv[i][0], paths);
+                    }
+                }
+            }
+
+            let optional = self.optional_tail.is_some_and(|from| i > 0 && i >= from);
+            let mut child = match spawn_result {
+                Ok(child) => child,
+                Err(e) if optional && e.kind() == io::ErrorKind::NotFound => {
+                    // End the pipeline at the previous stage, capturing its
+                    // output unless an earlier stage is already captured.
+                    if let Some(input) = tee_input {
+                        let (reader, writer) = io::pipe().map_err(Error::from_io)?;
+                        spawned.threads.push(thread::spawn(move || {
+                            tee(input, None::<ChildStdin>, writer)
+                        }));
+                        spawned.stdout = Some(Box::new(reader));
+                    } else if let Some(mut input) = input {
+                        if capture >= i {
+                            spawned.stdout = Some(Box::new(input));
+                        } else {
+                            spawned.threads.push(thread::spawn(move || {
+                                let _ = io::copy(&mut input, &mut io::sink());
+                            }));
+                        }
+                    }
+                    return Ok(spawned);
+                }
+                Err(e) => {
+                    // The read is bounded, so an earlier stage that keeps
+                    // running does not hold up the error.
+                    let partial = input
+                        .or(tee_input)
+                        .map(read_partial)
+                        .filter(|output| !output.is_empty());
+                    let err = if i == last {
+                        Error::from_io(e)
+                    } else {
+                        Error::new(
+                            ErrorKind::ExecError,
+                            Some(-1),
+                            format!("spawning failed: {}", e).as_str(),
+                        )
+                    };
+                    return Err(match partial {
+                        Some(output) => err.with_partial_output(output),
+                        None => err,
+                    });
+                }
+            };
+
+            #[cfg(unix)]
+            if let Some((mut reader, writer)) = pid_pipe {
+                drop(writer);
+                let mut pid = [0; 4];
+                let read = reader.read_exact(&mut pid);
+                // The intermediate process exits as soon as it has forked.
+                let _ = child.wait();
+                if let Err(e) = read {
+                    return Err(Error::from_io(e));
+                }
+                spawned.detached_pids.push(u32::from_ne_bytes(pid));
+            }
+
+            if let Some(StdinReader(reader)) = stdin_reader {
+                if let Some(stdin) = child.stdin.take() {
+                    let ignore_write_errors = self.ignore_stdin_write_errors;
+                    spawned.feeders.push(thread::spawn(move || {
+                        feed(reader, stdin, ignore_write_errors)
+                    }));
+                }
+            }
+            if let Some(reader) = stage_reader {
+                if let Some(stdin) = child.stdin.take() {
+                    let ignore_write_errors = self.ignore_stdin_write_errors;
+                    spawned.feeders.push(thread::spawn(move || {
+                        feed(reader, stdin, ignore_write_errors)
+                    }));
+                }
+            }
+
+            if let Some(input) = tee_input {
+                let (reader, writer) = io::pipe().map_err(Error::from_io)?;
+                let stdin = child.stdin.take();
+                spawned
+                    .threads
+                    .push(thread::spawn(move || tee(input, stdin, writer)));
+                spawned.stdout = Some(Box::new(reader));
+            }
+
+            if i == last && i == capture {
+                spawned.stdout = child
+                    .stdout
+                    .take()
+                    .map(|s| Box::new(s) as Box<dyn Read + Send>);
+            }
+            prev_stdout = next_stdout;
+            spawned.stderr.extend(child.stderr.take());
+
+            #[cfg(unix)]
+            if let Some(forwarder) = &mut spawned.forwarder {
+                if i == 0 || self.is_detached() {
+                    if let Err(e) = forwarder.add(child.id() as i32) {
+                        let _ = child.kill();
+                        let _ = child.wait();
+                        return Err(Error::from_io(e));
+                    }
+                }
+            }
+
+            spawned.children.push(child);
+        }
+
+        Ok(spawned)
+    }
+}
+
+// PipelineHandle
+//_____________________________________________________________________________
+
+/// A running pipeline, returned by `Pipeline::spawn()`. Dropping the handle
+/// without calling `wait()` kills and reaps any commands still running.
+#[derive(Debug)]
+pub struct PipelineHandle {
+    spawned: Option<Spawned>,
+    deadline: Option<Instant>,
+}
+
+impl PipelineHandle {
+    /// Return the process id of the final command.
+    pub fn pid(&self) -> u32 {
+        *self.pids().last().unwrap_or(&0)
+    }
+
+    /// Return the process ids of every command, in pipeline order.
+    pub fn pids(&self) -> Vec<u32> {
+        match &self.spawned {
+            Some(spawned) => spawned.children.iter().map(|c| c.id()).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Kill every command that is still running.
+    pub fn kill(&mut self) {
+        if let Some(spawned) = self.spawned.as_mut() {
+            spawned.kill();
+        }
+    }
+
+    // Take the first command's piped stdin and a reader for the captured
+    // stdout, leaving the handle responsible for the exit status.
+    pub(crate) fn take_stdio(&mut self) -> (Option<ChildStdin>, PipelineReader) {
+        let spawned = self.spawned.as_mut();
+        let (stdin, stdout) = match spawned {
+            Some(spawned) => (
+                spawned.children.first_mut().and_then(|c| c.stdin.take()),
+                spawned.stdout.take(),
+            ),
+            None => (None, None),
+        };
+        let reader = PipelineReader {
+            stdout,
+            handle: PipelineHandle {
+                spawned: None,
+                deadline: None,
+            },
+        };
+        (stdin, reader)
+    }
+
+    /// Wait for the pipeline to exit and return the captured stdout, with the
+    /// same error handling as `Pipeline::output()`, including the deadline.
+    pub fn wait(mut self) -> Result<String, Error> {
+        let mut spawned = match self.spawned.take() {
+            Some(spawned) => spawned,
+            None => return Ok(String::new()),
+        };
+        let stdout = spawned.read_to_end(self.deadline)?;
+        check_status(spawned.wait_until(self.deadline)?)?;
+        decode_utf8(stdout)
+    }
+}
+
+impl Drop for PipelineHandle {
+    fn drop(&mut self) {
+        self.kill();
+    }
+}
+
+// PipelineReader
+//_____________________________________________________________________________
+
+/// A running pipeline whose captured stdout is read through `Read`. Once the
+/// output has been read to the end, the next read waits for the pipeline to
+/// exit, and a failure such as a non-zero exit code is returned as an
+/// io::Error wrapping the shutil::Error. Dropping the reader kills and reaps
+/// any commands still running.
+///
+/// A reader returned alongside a PipelineHandle, as by
+/// `pipe_interactive_handle()`, only reads the output, and the handle reports
+/// the exit status instead.
+pub struct PipelineReader {
+    stdout: Option<Box<dyn Read + Send>>,
+    handle: PipelineHandle,
+}
+
+impl PipelineReader {
+    pub(crate) fn new(mut handle: PipelineHandle) -> PipelineReader {
+        PipelineReader {
+            stdout: handle.spawned.as_mut().and_then(|s| s.stdout.take()),
+            handle,
+        }
+    }
+
+    /// Return the running pipeline, for example to look up its process ids.
+    pub fn handle(&self) -> &PipelineHandle {
+        &self.handle
+    }
+}
+
+impl Read for PipelineReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(stdout) = self.stdout.as_mut() {
+            let n = stdout.read(buf)?;
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+            self.stdout = None;
+        }
+        if let Some(spawned) = self.handle.spawned.take() {
+            spawned
+                .wait()
+                .and_then(check_status)
+                .map_err(io::Error::other)?;
+        }
+        Ok(0)
+    }
+}
+
+impl fmt::Debug for PipelineReader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PipelineReader")
+            .field("handle", &self.handle)
+            .finish_non_exhaustive()
+    }
+}
+
+impl fmt::Debug for Spawned {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Spawned")
+            .field("children", &self.children)
+            .field("argv", &self.argv)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Spawned {
+    // Wait for every stage to exit and return the final command's status.
+    pub(crate) fn wait(mut self) -> Result<ExitStatus, Error> {
+        let mut last_status = None;
+        for child in self.children.iter_mut() {
+            last_status = Some(child.wait().map_err(Error::from_io)?);
+        }
+        self.join_threads()?;
+        match last_status {
+            Some(status) => Ok(status),
+            None => Err(Error::new(
+                ErrorKind::InvalidFormatError,
+                Some(-1),
+                "no commands supplied",
+            )),
+        }
+    }
+
+    // Check whether every stage has exited and stdin has been fed without
+    // blocking, returning the final command's status once they have. A feeder
+    // can outlive the stages while it waits on a slow source, and is abandoned
+    // rather than joined if the caller gives up.
+    pub(crate) fn try_wait(&mut self) -> Result<Option<ExitStatus>, Error> {
+        let mut last_status = None;
+        for child in self.children.iter_mut() {
+            match child.try_wait().map_err(Error::from_io)? {
+                Some(status) => last_status = Some(status),
+                None => return Ok(None),
+            }
+        }
+        if self.feeders.iter().any(|feeder| !feeder.is_finished()) {
+            return Ok(None);
+        }
+        self.join_threads()?;
+        Ok(last_status)
+    }
+
+    // Wait for every stage to exit and return the final command's status along
+    // with its resource usage. The final command is reaped with wait4(), since
+    // std does not expose rusage.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn wait_rusage(mut self) -> Result<(ExitStatus, RUsage), Error> {
+        use std::os::unix::process::ExitStatusExt;
+
+        let last = match self.children.pop() {
+            Some(child) => child,
+            None => {
+                return Err(Error::new(
+                    ErrorKind::InvalidFormatError,
+                    Some(-1),
+                    "no commands supplied",
+                ))
+            }
+        };
+        for child in self.children.iter_mut() {
+            child.wait().map_err(Error::from_io)?;
+        }
+
+        let mut status = 0;
+        // SAFETY: rusage is plain old data, so all zeroes is a valid value.
+        let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+        loop {
+            // SAFETY: the pointers are valid for the duration of the call and
+            // the pid belongs to a child that has not been reaped.
+            let ret = unsafe { libc::wait4(last.id() as libc::pid_t, &mut status, 0, &mut usage) };
+            if ret >= 0 {
+                break;
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(Error::from_io(err));
+            }
+        }
+        self.join_threads()?;
+
+        let timeval = |tv: libc::timeval| Duration::new(tv.tv_sec as u64, tv.tv_usec as u32 * 1000);
+        let usage = RUsage {
+            // ru_maxrss is reported in kilobytes on Linux.
+            max_rss: usage.ru_maxrss as u64 * 1024,
+            user_time: timeval(usage.ru_utime),
+            system_time: timeval(usage.ru_stime),
+        };
+        Ok((ExitStatus::from_raw(status), usage))
+    }
+
+    // Join the helper threads once every stage has exited, returning an error
+    // if feeding stdin failed.
+    fn join_threads(&mut self) -> Result<(), Error> {
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+        let mut result = Ok(());
+        for feeder in self.feeders.drain(..) {
+            let err = match feeder.join() {
+                Ok(Ok(())) => continue,
+                Ok(Err(e)) => Error::from_io(e),
+                Err(_e) => Error::new(ErrorKind::UnknownError, None, "stdin feeder panicked"),
+            };
+            if result.is_ok() {
+                result = Err(err);
+            }
+        }
+        result
+    }
+
+    // Read the piped stderr of every stage from a background thread, returning
+    // the combined text in pipeline order. Returns None if stderr is not piped.
+    pub(crate) fn read_stderr(&mut self) -> Option<JoinHandle<String>> {
+        if self.stderr.is_empty() {
+            return None;
+        }
+        let readers: Vec<JoinHandle<Vec<u8>>> = self
+            .stderr
+            .drain(..)
+            .map(|mut stderr| {
+                thread::spawn(move || {
+                    let mut buf = Vec::new();
+                    let _ = stderr.read_to_end(&mut buf);
+                    buf
+                })
+            })
+            .collect();
+        Some(thread::spawn(move || {
+            let mut combined = Vec::new();
+            for reader in readers {
+                combined.extend(reader.join().unwrap_or_default());
+            }
+            String::from_utf8_lossy(&combined).into_owned()
+        }))
+    }
+
+    // Read the captured stream to the end. If the deadline passes or a read
+    // stalls for longer than `read_timeout` first, kill every stage and return
+    // a TimeoutError with the output read so far attached.
+    pub(crate) fn read_to_end(&mut self, deadline: Option<Instant>) -> Result<Vec<u8>, Error> {
+        let mut stdout = Vec::new();
+        match self.copy_stdout(&mut stdout, deadline) {
+            Ok(()) => Ok(stdout),
+            Err(e) if e.kind() == ErrorKind::TimeoutError => {
+                Err(e.with_partial_output(String::from_utf8_lossy(&stdout).into_owned()))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    // Copy the captured stream into `writer` as it is produced, then flush it.
+    // The deadline, `read_timeout` and SIGINT are checked between chunks, and
+    // whichever fires kills every stage and returns its error. Failing to read
+    // or write also kills every stage.
+    pub(crate) fn copy_stdout(
+        &mut self,
+        writer: &mut dyn Write,
+        deadline: Option<Instant>,
+    ) -> Result<(), Error> {
+        let mut reader = match self.stdout.take() {
+            Some(reader) => reader,
+            None => return Ok(()),
+        };
+
+        if deadline.is_none() && self.read_timeout.is_none() && !self.cancellable() {
+            if let Err(e) = io::copy(&mut reader, writer).and_then(|_| writer.flush()) {
+                self.kill();
+                return Err(Error::from_io(e));
+            }
+            return Ok(());
+        }
+
+        // The reader thread is not joined on timeout, since the pipe may be
+        // held open by a grandchild that survives the kill.
+        let rx = read_chunks(reader);
+        let mut last_read = Instant::now();
+        loop {
+            let stall_deadline = self.read_timeout.map(|timeout| last_read + timeout);
+            let mut wait = match stall_deadline.into_iter().chain(deadline).min() {
+                Some(until) => until.saturating_duration_since(Instant::now()),
+                None => WAIT_POLL_INTERVAL,
+            };
+            if self.cancellable() {
+                wait = wait.min(WAIT_POLL_INTERVAL);
+            }
+            match rx.recv_timeout(wait) {
+                Ok(Ok(chunk)) => {
+                    if let Err(e) = writer.write_all(&chunk) {
+                        self.kill();
+                        return Err(Error::from_io(e));
+                    }
+                    last_read = Instant::now();
+                }
+                Ok(Err(e)) => {
+                    self.kill();
+                    return Err(Error::from_io(e));
+                }
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    if let Err(e) = writer.flush() {
+                        self.kill();
+                        return Err(Error::from_io(e));
+                    }
+                    return Ok(());
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    // Output copied before the error is left in the writer.
+                    let mut result = self.check_interrupted(deadline);
+                    if result.is_ok() && stall_deadline.is_some_and(|until| Instant::now() >= until)
+                    {
+                        self.kill();
+                        result = Err(Error::new(ErrorKind::TimeoutError, None, "read timed out"));
+                    }
+                    if result.is_err() {
+                        let _ = writer.flush();
+                        return result;
+                    }
+                }
+            }
+        }
+    }
+
+    // Wait for every stage to exit. If the deadline passes first, kill every
+    // stage and return a TimeoutError.
+    pub(crate) fn wait_until(mut self, deadline: Option<Instant>) -> Result<ExitStatus, Error> {
+        if deadline.is_none() && !self.cancellable() {
+            return self.wait();
+        }
+        loop {
+            if let Some(status) = self.try_wait()? {
+                // SIGINT may also have reached the stages and ended them
+                // before it was noticed.
+                self.check_cancelled()?;
+                return Ok(status);
+            }
+            self.check_interrupted(deadline)?;
+            thread::sleep(WAIT_POLL_INTERVAL);
+        }
+    }
+
+    // Kill every stage and return an error if the deadline has passed or
+    // SIGINT has been received.
+    fn check_interrupted(&mut self, deadline: Option<Instant>) -> Result<(), Error> {
+        self.check_cancelled()?;
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            self.kill();
+            return Err(timeout_error());
+        }
+        Ok(())
+    }
+
+    // Kill every stage and return a Cancelled error if SIGINT has been
+    // received.
+    fn check_cancelled(&mut self) -> Result<(), Error> {
+        #[cfg(unix)]
+        if self.cancellable() && SIGINT_RECEIVED.load(Ordering::SeqCst) {
+            self.kill();
+            return Err(Error::new(
+                ErrorKind::Cancelled,
+                None,
+                "interrupted by SIGINT",
+            ));
+        }
+        Ok(())
+    }
+
+    // Whether waiting should stop when SIGINT is received.
+    #[cfg(unix)]
+    fn cancellable(&self) -> bool {
+        self.sigint.is_some()
+    }
+
+    #[cfg(not(unix))]
+    fn cancellable(&self) -> bool {
+        false
+    }
+
+    // Give up the running stages of a detached pipeline, returning their pids.
+    // They have already been reparented away from this process, so there is
+    // nothing left to reap.
+    #[cfg(unix)]
+    pub(crate) fn detach(mut self) -> Vec<u32> {
+        std::mem::take(&mut self.detached_pids)
+    }
+
+    // Kill and reap every stage that has been spawned so far.
+    pub(crate) fn kill(&mut self) {
+        for child in self.children.iter_mut() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+        #[cfg(unix)]
+        for &pid in &self.detached_pids {
+            // SAFETY: kill has no memory safety requirements.
+            unsafe {
+                libc::kill(pid as libc::pid_t, libc::SIGKILL);
+            }
+        }
+    }
+}
+
+impl Drop for Spawned {
+    fn drop(&mut self) {
+        // Stages that have already been reaped are skipped by Child::kill().
+        self.kill();
+    }
+}
+
+// Return a TimeoutError if the deadline has passed.
+pub(crate) fn check_deadline(deadline: Option<Instant>) -> Result<(), Error> {
+    match deadline {
+        Some(deadline) if Instant::now() >= deadline => Err(timeout_error()),
+        _ => Ok(()),
+    }
+}
+
+pub(crate) fn timeout_error() -> Error {
+    Error::new(ErrorKind::TimeoutError, None, "deadline exceeded")
+}
+
+// Return an ExecError for a non-zero exit status.
+pub(crate) fn check_status(status: ExitStatus) -> Result<(), Error> {
+    if !status.success() {
+        return Err(Error::new(
+            ErrorKind::ExecError,
+            status.code(),
+            "non-zero exit code",
+        ));
+    }
+    Ok(())
+}
+
+// Return the exit code of a status, or an ExecError if the process was killed
+// by a signal.
+pub(crate) fn exit_code(status: ExitStatus) -> Result<i32, Error> {
+    match status.code() {
+        Some(code) => Ok(code),
+        None => Err(Error::new(
+            ErrorKind::ExecError,
+            None,
+            "terminated by signal",
+        )),
+    }
+}
+
+// Set by the SIGINT handler installed for Pipeline::handle_ctrl_c().
+#[cfg(unix)]
+static SIGINT_RECEIVED: AtomicBool = AtomicBool::new(false);
+
+// The number of running pipelines that handle SIGINT, and the handler that was
+// installed before the first of them started.
+#[cfg(unix)]
+static SIGINT_STATE: Mutex<(usize, Option<libc::sigaction>)> = Mutex::new((0, None));
+
+#[cfg(unix)]
+extern "C" fn on_sigint(_signal: libc::c_int) {
+    SIGINT_RECEIVED.store(true, Ordering::SeqCst);
+}
+
+// Keeps the SIGINT handler installed while a pipeline that handles SIGINT is
+// running.
+#[cfg(unix)]
+#[derive(Debug)]
+struct SigintGuard;
+
+#[cfg(unix)]
+impl SigintGuard {
+    fn install() -> io::Result<SigintGuard> {
+        let mut state = lock(&SIGINT_STATE);
+        if state.0 == 0 {
+            SIGINT_RECEIVED.store(false, Ordering::SeqCst);
+            // SAFETY: sigaction is plain old data, and the handler only
+            // stores to an atomic, which is async-signal-safe.
+            unsafe {
+                let mut action: libc::sigaction = std::mem::zeroed();
+                action.sa_sigaction = on_sigint as *const () as libc::sighandler_t;
+                action.sa_flags = libc::SA_RESTART;
+                libc::sigemptyset(&mut action.sa_mask);
+                let mut previous: libc::sigaction = std::mem::zeroed();
+                if libc::sigaction(libc::SIGINT, &action, &mut previous) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                state.1 = Some(previous);
+            }
+        }
+        state.0 += 1;
+        Ok(SigintGuard)
+    }
+}
+
+#[cfg(unix)]
+impl Drop for SigintGuard {
+    fn drop(&mut self) {
+        let mut state = lock(&SIGINT_STATE);
+        state.0 -= 1;
+        if state.0 == 0 {
+            if let Some(previous) = state.1.take() {
+                // SAFETY: restores the handler saved by install().
+                unsafe {
+                    libc::sigaction(libc::SIGINT, &previous, std::ptr::null_mut());
+                }
+            }
+        }
+    }
+}
+
+// The process groups that signals are forwarded to, for
+// Pipeline::forward_signals(). The handler cannot take a lock, so each slot
+// holds the group and the mask of signals to forward to it in atomics, with a
+// pgid of 0 marking a free slot.
+#[cfg(unix)]
+struct ForwardSlot {
+    pgid: AtomicI32,
+    mask: AtomicU64,
+}
+
+#[cfg(unix)]
+static FORWARD_SLOTS: [ForwardSlot; 64] = [const {
+    ForwardSlot {
+        pgid: AtomicI32::new(0),
+        mask: AtomicU64::new(0),
+    }
+}; 64];
+
+// Each forwarded signal, the number of running pipelines forwarding it, and
+// the handler that was installed before the first of them started.
+#[cfg(unix)]
+static FORWARD_STATE: Mutex<Vec<(i32, usize, libc::sigaction)>> = Mutex::new(Vec::new());
+
+#[cfg(unix)]
+extern "C" fn on_forwarded_signal(signal: libc::c_int) {
+    // The handler replaces on_sigint while it is installed.
+    if signal == libc::SIGINT {
+        SIGINT_RECEIVED.store(true, Ordering::SeqCst);
+    }
+    let bit = 1u64 << (signal - 1);
+    for slot in &FORWARD_SLOTS {
+        let pgid = slot.pgid.load(Ordering::SeqCst);
+        if pgid != 0 && slot.mask.load(Ordering::SeqCst) & bit != 0 {
+            // SAFETY: kill is async-signal-safe.
+            unsafe {
+                libc::kill(-pgid, signal);
+            }
+        }
+    }
+}
+
+// Keeps the forwarding handlers installed, and the pipeline's process groups
+// registered, while a pipeline that forwards signals is running.
+#[cfg(unix)]
+#[derive(Debug)]
+struct SignalForwarder {
+    signals: Vec<i32>,
+    mask: u64,
+    slots: Vec<usize>,
+}
+
+#[cfg(unix)]
+impl SignalForwarder {
+    fn install(signals: &[i32]) -> io::Result<SignalForwarder> {
+        let mut forwarder = SignalForwarder {
+            signals: Vec::new(),
+            mask: 0,
+            slots: Vec::new(),
+        };
+        // Declared after the forwarder, so the lock is released before an
+        // early return drops the forwarder and uninstalls its handlers.
+        let mut state = lock(&FORWARD_STATE);
+        for &signal in signals {
+            if forwarder.signals.contains(&signal) {
+                continue;
+            }
+            match state.iter_mut().find(|entry| entry.0 == signal) {
+                Some(entry) => entry.1 += 1,
+                // SAFETY: sigaction is plain old data, and the handler only
+                // stores to atomics and calls kill, which are async-signal-safe.
+                None => unsafe {
+                    let mut action: libc::sigaction = std::mem::zeroed();
+                    action.sa_sigaction = on_forwarded_signal as *const () as libc::sighandler_t;
+                    action.sa_flags = libc::SA_RESTART;
+                    libc::sigemptyset(&mut action.sa_mask);
+                    let mut previous: libc::sigaction = std::mem::zeroed();
+                    if libc::sigaction(signal, &action, &mut previous) < 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                    state.push((signal, 1, previous));
+                },
+            }
+            forwarder.signals.push(signal);
+            forwarder.mask |= 1 << (signal - 1);
+        }
+        drop(state);
+        Ok(forwarder)
+    }
+
+    // Forward the signals to the process group `pgid` from now on.
+    fn add(&mut self, pgid: i32) -> io::Result<()> {
+        let _state = lock(&FORWARD_STATE);
+        let index = FORWARD_SLOTS
+            .iter()
+            .position(|slot| slot.pgid.load(Ordering::SeqCst) == 0)
+            .ok_or_else(|| io::Error::other("too many pipelines forwarding signals"))?;
+        FORWARD_SLOTS[index].mask.store(self.mask, Ordering::SeqCst);
+        FORWARD_SLOTS[index].pgid.store(pgid, Ordering::SeqCst);
+        self.slots.push(index);
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+impl Drop for SignalForwarder {
+    fn drop(&mut self) {
+        let mut state = lock(&FORWARD_STATE);
+        for &index in &self.slots {
+            FORWARD_SLOTS[index].pgid.store(0, Ordering::SeqCst);
+            FORWARD_SLOTS[index].mask.store(0, Ordering::SeqCst);
+        }
+        for signal in &self.signals {
+            if let Some(index) = state.iter().position(|entry| entry.0 == *signal) {
+                state[index].1 -= 1;
+                if state[index].1 == 0 {
+                    let (signal, _, previous) = state.remove(index);
+                    // SAFETY: restores the handler saved by install().
+                    unsafe {
+                        libc::sigaction(signal, &previous, std::ptr::null_mut());
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Build a seccomp-bpf program that allows `syscalls` and the calls needed to
+// exec and exit, and kills the process on any other call. Returns None if the
+// architecture is not supported.
+#[cfg(all(feature = "seccomp", target_os = "linux"))]
+fn seccomp_filter(syscalls: &[i64]) -> Option<Vec<libc::sock_filter>> {
+    // AUDIT_ARCH_* values from linux/audit.h.
+    let arch: u32 = if cfg!(target_arch = "x86_64") {
+        0xc000_003e
+    } else if cfg!(target_arch = "aarch64") {
+        0xc000_00b7
+    } else if cfg!(target_arch = "riscv64") {
+        0xc000_00f3
+    } else if cfg!(target_arch = "x86") {
+        0x4000_0003
+    } else if cfg!(target_arch = "arm") {
+        0x4000_0028
+    } else {
+        return None;
+    };
+
+    // Offsets of the nr and arch fields of struct seccomp_data.
+    const NR_OFFSET: u32 = 0;
+    const ARCH_OFFSET: u32 = 4;
+
+    let stmt = |code: u32, k: u32| libc::sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    };
+    let jeq = |k: u32, jt: u8, jf: u8| libc::sock_filter {
+        code: (libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K) as u16,
+        jt,
+        jf,
+        k,
+    };
+    let load = libc::BPF_LD | libc::BPF_W | libc::BPF_ABS;
+    let ret = libc::BPF_RET | libc::BPF_K;
+
+    let mut allowed = vec![libc::SYS_execve, libc::SYS_exit, libc::SYS_exit_group];
+    allowed.extend(syscalls);
+
+    let mut filter = vec![
+        stmt(load, ARCH_OFFSET),
+        jeq(arch, 1, 0),
+        stmt(ret, libc::SECCOMP_RET_KILL_PROCESS),
+        stmt(load, NR_OFFSET),
+    ];
+    for nr in allowed {
+        // Numbers that do not fit can never match, so they are left out.
+        if let Ok(nr) = u32::try_from(nr) {
+            filter.push(jeq(nr, 0, 1));
+            filter.push(stmt(ret, libc::SECCOMP_RET_ALLOW));
+        }
+    }
+    filter.push(stmt(ret, libc::SECCOMP_RET_KILL_PROCESS));
+    Some(filter)
+}
+
+// Quote a word for a POSIX shell if it contains anything but characters that
+// are never special.
+pub(crate) fn shell_escape(word: &str) -> Cow<'_, str> {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c);
+    if !word.is_empty() && word.chars().all(safe) {
+        return Cow::Borrowed(word);
+    }
+    Cow::Owned(format!("'{}'", word.replace('\'', "'\\''")))
+}
+
+// Fork a detached command again just before it execs, so the command is not a
+// session leader and is reparented to init once the intermediate process
+// exits. The intermediate writes the command's pid to `pid_pipe`, which is
+// closed on exec.
+#[cfg(unix)]
+fn double_fork(command: &mut Command, pid_pipe: &io::PipeWriter) {
+    use std::os::fd::AsRawFd;
+    use std::os::unix::process::CommandExt;
+
+    let fd = pid_pipe.as_raw_fd();
+    // SAFETY: only fork, write and _exit are called, which are
+    // async-signal-safe.
+    unsafe {
+        command.pre_exec(move || match libc::fork() {
+            -1 => Err(io::Error::last_os_error()),
+            0 => Ok(()),
+            pid => {
+                let bytes = (pid as u32).to_ne_bytes();
+                libc::write(fd, bytes.as_ptr() as *const libc::c_void, bytes.len());
+                libc::_exit(0);
+            }
+        });
+    }
+}
+
+// Decode captured stdout, returning a UnicodeDecodeError if it is not utf-8,
+// with the valid prefix of the output attached.
+pub(crate) fn decode_utf8(stdout: Vec<u8>) -> Result<String, Error> {
+    match String::from_utf8(stdout) {
+        Ok(v) => Ok(v),
+        Err(e) => {
+            let valid_up_to = e.utf8_error().valid_up_to();
+            let mut bytes = e.into_bytes();
+            bytes.truncate(valid_up_to);
+            let prefix = String::from_utf8(bytes).unwrap_or_default();
+            Err(
+                Error::new(ErrorKind::UnicodeDecodeError, None, "utf-8 decode failed")
+                    .with_valid_prefix(prefix),
+            )
+        }
+    }
+}
+
+// Expand `$VAR` and `${VAR}` references in `word` from `map`. A `$` that does
+// not start a reference, including an empty `${}`, is left as it is.
+fn expand_vars(word: &str, map: &HashMap<String, String>) -> String {
+    let is_name_char = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let mut expanded = String::new();
+    let mut rest = word;
+
+    while let Some(pos) = rest.find('$') {
+        expanded.push_str(&rest[..pos]);
+        let after = &rest[pos + 1..];
+
+        let (name, remaining) = if let Some(braced) = after.strip_prefix('{') {
+            match braced.find('}') {
+                Some(end) if end > 0 => (&braced[..end], &braced[end + 1..]),
+                _ => ("", after),
+            }
+        } else if after.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+            let end = after.find(|c| !is_name_char(c)).unwrap_or(after.len());
+            (&after[..end], &after[end..])
+        } else {
+            ("", after)
+        };
+
+        if name.is_empty() {
+            expanded.push('$');
+        } else if let Some(value) = map.get(name) {
+            expanded.push_str(value);
+        }
+        rest = remaining;
+    }
+
+    expanded.push_str(rest);
+    expanded
+}
+
+// Read the output an earlier stage has produced, up to PARTIAL_OUTPUT_LIMIT
+// bytes, so it can be attached to a spawn error. Reading stops at end of file
+// or after PARTIAL_OUTPUT_GRACE, whichever comes first.
+fn read_partial(reader: PipeReader) -> String {
+    let chunks = read_chunks(Box::new(reader.take(PARTIAL_OUTPUT_LIMIT)));
+    let deadline = Instant::now() + PARTIAL_OUTPUT_GRACE;
+    let mut output = Vec::new();
+    while let Ok(Ok(chunk)) =
+        chunks.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+    {
+        output.extend(chunk);
+    }
+    String::from_utf8_lossy(&output).into_owned()
+}
+
+// Resize a pipe's buffer. Either end of the pipe may be used.
+#[cfg(target_os = "linux")]
+fn set_pipe_capacity(pipe: &impl std::os::fd::AsRawFd, bytes: usize) -> Result<(), Error> {
+    let size = libc::c_int::try_from(bytes).unwrap_or(libc::c_int::MAX);
+    if unsafe { libc::fcntl(pipe.as_raw_fd(), libc::F_SETPIPE_SZ, size) } < 0 {
+        return Err(Error::from_io(io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+// Paths found by find_in_path(), keyed by the name and the value of PATH.
+type BinaryCache = HashMap<(String, OsString), PathBuf>;
+
+fn binary_cache() -> &'static Mutex<BinaryCache> {
+    static CACHE: OnceLock<Mutex<BinaryCache>> = OnceLock::new();
+    CACHE.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+// Find an executable by name in the directories listed in PATH.
+pub(crate) fn find_in_path(name: &str) -> Option<PathBuf> {
+    search_paths(name, &env::var_os("PATH")?)
+}
+
+// Find an executable by name in the directories listed in PATH, reusing the
+// result of an earlier lookup with the same PATH.
+pub(crate) fn find_in_path_cached(name: &str) -> Option<PathBuf> {
+    find_in(name, env::var_os("PATH")?)
+}
+
+// Find an executable by name in the directories listed in `paths`, through
+// the cache. Only successful lookups are cached, so a binary installed later
+// is still found.
+fn find_in(name: &str, paths: OsString) -> Option<PathBuf> {
+    let key = (name.to_string(), paths);
+    if let Some(path) = lock(binary_cache()).get(&key) {
+        return Some(path.clone());
+    }
+    let path = search_paths(name, &key.1)?;
+    lock(binary_cache()).insert(key, path.clone());
+    Some(path)
+}
+
+fn search_paths(name: &str, paths: &OsStr) -> Option<PathBuf> {
+    env::split_paths(paths)
+        .map(|dir| dir.join(name))
+        .find(|path| is_executable(path))
+}
+
+// Forget the cached path of one binary, after it could not be executed.
+fn forget_binary(name: &str, paths: OsString) {
+    lock(binary_cache()).remove(&(name.to_string(), paths));
+}
+
+// Whether `path` is a regular file that can be executed.
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+
+    path.metadata()
+        .is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+// Forget every path cached by find_in_path().
+pub(crate) fn clear_binary_cache() {
+    lock(binary_cache()).clear();
+}
+
+// Lock a mutex, ignoring poisoning since the data is a plain cache.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// Read from `reader` in a background thread, sending each chunk as it arrives.
+// The channel disconnects at end of file or after an error is sent. Callers
+// that time out do not join the thread, since the pipe may be held open by a
+// grandchild that survives the kill.
+fn read_chunks(mut reader: Box<dyn Read + Send>) -> mpsc::Receiver<io::Result<Vec<u8>>> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut buf = [0; 8192];
+        loop {
+            let chunk = match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => Ok(buf[..n].to_vec()),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => Err(e),
+            };
+            let failed = chunk.is_err();
+            if tx.send(chunk).is_err() || failed {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+// Copy a stdin source into the first command's stdin. A broken pipe means the
+// command has stopped reading, which is only an error if write errors are not
+// ignored. Any other write error, and failing to read the source, is always
+// returned.
+fn feed(
+    mut reader: Box<dyn Read + Send>,
+    mut stdin: ChildStdin,
+    ignore_write_errors: bool,
+) -> io::Result<()> {
+    let mut buf = [0u8; 8192];
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        match stdin.write_all(&buf[..n]) {
+            Ok(()) => {}
+            Err(e) if ignore_write_errors && e.kind() == io::ErrorKind::BrokenPipe => return Ok(()),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// Run a transform_streaming() closure from `input` to `output`, treating the
+// next stage closing its stdin as the end of the stream.
+fn run_transform(
+    transform: Box<TransformFn>,
+    input: PipeReader,
+    output: io::PipeWriter,
+) -> io::Result<()> {
+    let mut reader = BufReader::with_capacity(64 * 1024, input);
+    let mut writer = BufWriter::with_capacity(64 * 1024, output);
+    match transform(&mut reader, &mut writer).and_then(|_| writer.flush()) {
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        result => result,
+    }
+}
+
+// Copy a captured stage's stdout to both the next stage and the capture pipe.
+// If the next stage stops reading, keep capturing so the output is complete.
+fn tee(mut input: PipeReader, mut next: Option<impl Write>, mut capture: impl Write) {
+    let mut buf = [0u8; 8192];
+    loop {
+        let n = match input.read(&mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => n,
+        };
+        if let Some(w) = next.as_mut() {
+            if w.write_all(&buf[..n]).is_err() {
+                next = None;
+            }
+        }
+        if capture.write_all(&buf[..n]).is_err() {
+            break;
+        }
+    }
+}
+
+// Tests
+//_____________________________________________________________________________
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_capture_stage() {
+        let path = std::env::temp_dir().join(format!("shutil-capture-{}", std::process::id()));
+        let output = Pipeline::new(vec![
+            vec!["echo", "foo"],
+            vec!["sh", "-c", &format!("rev > {}", path.display())],
+        ])
+        .capture_stage(0)
+        .output();
+
+        assert_eq!(output.unwrap(), "foo\n");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "oof\n");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unbuffered() {
+        use std::io::{BufRead, BufReader};
+        use std::time::{Duration, Instant};
+
+        // Without GNU stdbuf, unbuffered() runs the commands unmodified and
+        // there is nothing to test.
+        if find_in_path("stdbuf").is_none() {
+            return;
+        }
+
+        // grep block buffers when writing to a pipe, so without stdbuf the
+        // first line would only arrive once the script exits.
+        let mut pipeline = Pipeline::new(vec![
+            vec!["sh", "-c", "echo 1; sleep 2; echo 2"],
+            vec!["grep", "."],
+        ])
+        .unbuffered(true);
+
+        let start = Instant::now();
+        let mut spawned = pipeline.start().unwrap();
+        let mut reader = BufReader::new(spawned.stdout.take().unwrap());
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "1\n");
+        assert!(start.elapsed() < Duration::from_millis(1500));
+
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "2\n");
+        assert!(spawned.wait().unwrap().success());
+    }
+
+    #[test]
+    fn test_expand_env_from() {
+        let map = HashMap::from([
+            ("A".to_string(), "1".to_string()),
+            ("B".to_string(), "2".to_string()),
+        ]);
+        let output = Pipeline::new(vec![vec!["echo", "$A-$B", "${A}x", "$C.", "$", "${}"]])
+            .expand_env_from(map)
+            .output();
+        assert_eq!(output.unwrap(), "1-2 1x . $ ${}\n");
+    }
+
+    #[test]
+    fn test_partial_output_on_spawn_error() {
+        let output = Pipeline::new(vec![vec!["echo", "foo"], vec!["/does/not/exist"]]).output();
+        let err = output.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::OsError);
+        assert_eq!(err.code(), Some(2));
+        assert_eq!(err.partial_output(), Some("foo\n"));
+    }
+
+    #[test]
+    fn test_partial_output_on_spawn_error_running() {
+        // The first stage would hold its stdout open for 3 seconds.
+        let start = Instant::now();
+        let output = Pipeline::new(vec![
+            vec!["sh", "-c", "echo hi; sleep 3"],
+            vec!["/does/not/exist"],
+        ])
+        .output();
+        let err = output.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::OsError);
+        assert_eq!(err.partial_output(), Some("hi\n"));
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_ionice() {
+        let mut pipeline = Pipeline::new(vec![vec!["sleep", "1"]])
+            .ionice(IoClass::Idle, 0)
+            .discard_stdout();
+        let mut spawned = pipeline.start().unwrap();
+        let pid = spawned.children[0].id().to_string();
+
+        let output = crate::pipe(vec![vec!["ionice", "-p", &pid]]);
+        spawned.kill();
+        assert_eq!(output.unwrap(), "idle\n");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_pipe_capacity() {
+        let output = Pipeline::new(vec![
+            vec!["head", "-c", "1000000", "/dev/zero"],
+            vec!["tr", "\\0", "a"],
+            vec!["wc", "-c"],
+        ])
+        .pipe_capacity(1024 * 1024)
+        .output();
+        assert_eq!(output.unwrap().trim(), "1000000");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_set_pipe_capacity() {
+        let (reader, _writer) = io::pipe().unwrap();
+        set_pipe_capacity(&reader, 256 * 1024).unwrap();
+        let size =
+            unsafe { libc::fcntl(std::os::fd::AsRawFd::as_raw_fd(&reader), libc::F_GETPIPE_SZ) };
+        assert_eq!(size, 256 * 1024);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_pipe_capacity_error_kills_started_stages() {
+        // Stage 0 has no pipe of its own, so it is running by the time the
+        // pipe after stage 1 fails to resize.
+        let output = Pipeline::new(vec![vec!["sleep", "7.25"], vec!["cat"], vec!["cat"]])
+            .stage_io(0, StdinMode::Null, StdoutMode::Null, StderrMode::Inherit)
+            .pipe_capacity(usize::MAX)
+            .output();
+        assert_eq!(output.unwrap_err().kind(), ErrorKind::OsError);
+
+        let running = fs::read_dir("/proc").unwrap().flatten().any(|entry| {
+            fs::read(entry.path().join("cmdline"))
+                .is_ok_and(|cmdline| cmdline == b"sleep\x007.25\x00")
+        });
+        assert!(!running);
+    }
+
+    #[test]
+    fn test_deadline_passed() {
+        let start = Instant::now();
+        let output = Pipeline::new(vec![vec!["sleep", "5"]])
+            .deadline(Instant::now() - Duration::from_millis(1))
+            .output();
+        assert_eq!(output.unwrap_err().kind(), ErrorKind::TimeoutError);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_deadline_exceeded() {
+        let start = Instant::now();
+        let output = Pipeline::new(vec![vec!["sleep", "5"], vec!["cat"]])
+            .deadline(Instant::now() + Duration::from_millis(200))
+            .output();
+        assert_eq!(output.unwrap_err().kind(), ErrorKind::TimeoutError);
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_deadline_met() {
+        let output = Pipeline::new(vec![vec!["echo", "foo"]])
+            .deadline(Instant::now() + Duration::from_secs(10))
+            .output();
+        assert_eq!(output.unwrap(), "foo\n");
+    }
+
+    // Reads `data` and then stalls for `stall` before reporting end of file.
+    struct SlowReader {
+        data: Option<&'static [u8]>,
+        stall: Duration,
+    }
+
+    impl Read for SlowReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.data.take() {
+                Some(data) => {
+                    buf[..data.len()].copy_from_slice(data);
+                    Ok(data.len())
+                }
+                None => {
+                    thread::sleep(self.stall);
+                    Ok(0)
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_deadline_slow_stdin() {
+        // head exits after the first byte, but feeding stdin is still waiting
+        // on the source when the deadline passes.
+        let start = Instant::now();
+        let output = Pipeline::new(vec![vec!["head", "-c", "1"]])
+            .stdin_reader(SlowReader {
+                data: Some(b"a"),
+                stall: Duration::from_secs(3),
+            })
+            .deadline(Instant::now() + Duration::from_millis(200))
+            .output();
+        assert_eq!(output.unwrap_err().kind(), ErrorKind::TimeoutError);
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_stdin_reader() {
+        let output = Pipeline::new(vec![vec!["rev"]])
+            .stdin_reader(io::Cursor::new("foo\nbar\n"))
+            .output();
+        assert_eq!(output.unwrap(), "oof\nrab\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_ignore_stdin_write_errors() {
+        let input = vec![b'a'; 1024 * 1024];
+        let output = Pipeline::new(vec![vec!["head", "-c", "3"]])
+            .stdin_reader(io::Cursor::new(input.clone()))
+            .ignore_stdin_write_errors(true)
+            .output();
+        assert_eq!(output.unwrap(), "aaa");
+
+        let output = Pipeline::new(vec![vec!["head", "-c", "3"]])
+            .stdin_reader(io::Cursor::new(input))
+            .ignore_stdin_write_errors(false)
+            .output();
+        let err = output.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::OsError);
+        assert_eq!(err.code(), Some(libc::EPIPE));
+    }
+
+    #[test]
+    fn test_to_writer() {
+        let mut out = Vec::new();
+        Pipeline::new(vec![vec!["echo", "foo"], vec!["rev"]])
+            .to_writer(&mut out)
+            .unwrap();
+        assert_eq!(out, b"oof\n");
+    }
+
+    #[test]
+    fn test_to_writer_deadline() {
+        let start = Instant::now();
+        let mut out = Vec::new();
+        let result = Pipeline::new(vec![vec!["sh", "-c", "echo first; sleep 3"]])
+            .deadline(Instant::now() + Duration::from_millis(200))
+            .to_writer(&mut out);
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::TimeoutError);
+        assert_eq!(out, b"first\n");
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_tcp_stream() {
+        use std::net::{Shutdown, TcpListener, TcpStream};
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            // Write in several pieces so the server sees partial reads.
+            for chunk in ["fo", "o\nba", "r\n"] {
+                stream.write_all(chunk.as_bytes()).unwrap();
+                thread::sleep(Duration::from_millis(10));
+            }
+            stream.shutdown(Shutdown::Write).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        });
+
+        let (mut stream, _addr) = listener.accept().unwrap();
+        Pipeline::new(vec![vec!["rev"]])
+            .stdin_reader(stream.try_clone().unwrap())
+            .to_writer(&mut stream)
+            .unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+
+        assert_eq!(client.join().unwrap(), "oof\nrab\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_restore_default_sigpipe() {
+        use std::os::unix::process::ExitStatusExt;
+
+        let mut pipeline =
+            Pipeline::new(vec![vec!["yes"], vec!["head", "-n", "1"]]).restore_default_sigpipe(true);
+        let mut spawned = pipeline.start().unwrap();
+        let stdout = spawned.read_to_end(None).unwrap();
+        assert_eq!(stdout, b"y\n");
+
+        let producer = spawned.children[0].wait().unwrap();
+        assert_eq!(producer.signal(), Some(libc::SIGPIPE));
+        assert!(spawned.wait().unwrap().success());
+    }
+
+    #[test]
+    fn test_output_with_argv() {
+        let map = HashMap::from([("NAME".to_string(), "world".to_string())]);
+        let (output, argv) = Pipeline::new(vec![vec!["echo", "hello $NAME"], vec!["rev"]])
+            .expand_env_from(map)
+            .output_with_argv()
+            .unwrap();
+        assert_eq!(output, "dlrow olleh\n");
+        assert_eq!(argv, vec![vec!["echo", "hello world"], vec!["rev"]]);
+    }
+
+    #[test]
+    #[ignore = "requires passwordless sudo"]
+    fn test_sudo() {
+        let output = Pipeline::new(vec![vec!["id", "-u"]]).sudo(true).output();
+        assert_eq!(output.unwrap(), "0\n");
+    }
+
+    #[test]
+    fn test_pipeline_handle_pids() {
+        let handle = Pipeline::new(vec![vec!["echo", "foo"], vec!["rev"], vec!["cat"]])
+            .spawn()
+            .unwrap();
+
+        let pids = handle.pids();
+        assert_eq!(pids.len(), 3);
+        assert!(pids.iter().all(|&pid| pid != 0));
+        assert!(pids[0] != pids[1] && pids[1] != pids[2] && pids[0] != pids[2]);
+        assert_eq!(handle.pid(), pids[2]);
+
+        assert_eq!(handle.wait().unwrap(), "oof\n");
+    }
+
+    #[test]
+    fn test_pipeline_handle_deadline() {
+        let start = Instant::now();
+        let handle = Pipeline::new(vec![vec!["sleep", "3"]])
+            .deadline(Instant::now() + Duration::from_millis(200))
+            .spawn()
+            .unwrap();
+        assert_eq!(handle.wait().unwrap_err().kind(), ErrorKind::TimeoutError);
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_success_when() {
+        let clean = |result: &ExecResult| result.code == Some(0) && result.stderr.is_empty();
+
+        let output = Pipeline::new(vec![vec!["echo", "foo"]])
+            .success_when(clean)
+            .output();
+        assert_eq!(output.unwrap(), "foo\n");
+
+        let output = Pipeline::new(vec![vec!["sh", "-c", "echo foo; echo warning >&2"]])
+            .success_when(clean)
+            .output();
+        let err = output.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ExecError);
+        assert_eq!(err.code(), Some(0));
+        assert_eq!(err.stderr(), Some("warning\n"));
+
+        let output = Pipeline::new(vec![vec!["sh", "-c", "exit 3"]])
+            .success_when(clean)
+            .output();
+        assert_eq!(output.unwrap_err().code(), Some(3));
+
+        let output = Pipeline::new(vec![vec!["sh", "-c", "echo foo; exit 3"]])
+            .success_when(|result| result.code == Some(3))
+            .output();
+        assert_eq!(output.unwrap(), "foo\n");
+    }
+
+    #[test]
+    fn test_empty_stage_index() {
+        let output = Pipeline::new(vec![vec!["echo", "a"], vec![]]).output();
+        let err = output.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidFormatError);
+        assert!(err
+            .to_string()
+            .contains("stage 1: no command binary supplied"));
+
+        let output = Pipeline::new(vec![vec![""], vec!["cat"]]).output();
+        let err = output.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidFormatError);
+        assert!(err
+            .to_string()
+            .contains("stage 0: no command binary supplied"));
+    }
+
+    #[test]
+    fn test_to_writer_timeout() {
+        let mut buf = Vec::new();
+        let result = Pipeline::new(vec![vec!["sh", "-c", "echo first; sleep 5; echo second"]])
+            .to_writer_timeout(&mut buf, Duration::from_millis(300));
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::TimeoutError);
+        assert_eq!(buf, b"first\n");
+    }
+
+    #[test]
+    fn test_to_writer_timeout_met() {
+        let mut buf = Vec::new();
+        Pipeline::new(vec![vec!["echo", "foo"], vec!["rev"]])
+            .to_writer_timeout(&mut buf, Duration::from_secs(5))
+            .unwrap();
+        assert_eq!(buf, b"oof\n");
+    }
+
+    #[cfg(all(feature = "seccomp", target_os = "linux"))]
+    #[test]
+    fn test_seccomp_allow_kills() {
+        use std::os::unix::process::ExitStatusExt;
+
+        // Only the baseline is allowed, so the loader's first call is fatal.
+        let status = Pipeline::new(vec![vec!["true"]])
+            .seccomp_allow(&[])
+            .start()
+            .unwrap()
+            .wait()
+            .unwrap();
+        assert_eq!(status.signal(), Some(libc::SIGSYS));
+    }
+
+    #[cfg(all(feature = "seccomp", target_os = "linux", target_arch = "x86_64"))]
+    #[test]
+    fn test_seccomp_allow() {
+        use std::os::unix::process::ExitStatusExt;
+
+        // Calls made by the dynamic loader and libc startup.
+        let startup = [
+            libc::SYS_access,
+            libc::SYS_arch_prctl,
+            libc::SYS_brk,
+            libc::SYS_close,
+            libc::SYS_fstat,
+            libc::SYS_getrandom,
+            libc::SYS_mmap,
+            libc::SYS_mprotect,
+            libc::SYS_munmap,
+            libc::SYS_newfstatat,
+            libc::SYS_openat,
+            libc::SYS_pread64,
+            libc::SYS_prlimit64,
+            libc::SYS_read,
+            libc::SYS_rseq,
+            libc::SYS_set_robust_list,
+            libc::SYS_set_tid_address,
+        ];
+        let status = Pipeline::new(vec![vec!["true"]])
+            .seccomp_allow(&startup)
+            .start()
+            .unwrap()
+            .wait()
+            .unwrap();
+        assert_eq!(status.code(), Some(0));
+
+        // Writing is not allowed, so echo is killed.
+        let status = Pipeline::new(vec![vec!["echo", "foo"]])
+            .seccomp_allow(&startup)
+            .start()
+            .unwrap()
+            .wait()
+            .unwrap();
+        assert_eq!(status.signal(), Some(libc::SIGSYS));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_command_uses_binary_cache() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = env::temp_dir().join(format!("shutil-resolve-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let binary = dir.join("shutil-resolved");
+        std::fs::write(&binary, "#!/bin/sh\necho found\n").unwrap();
+        std::fs::set_permissions(&binary, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let paths = dir.to_str().unwrap();
+
+        let pipeline = || {
+            Pipeline::new(vec![vec!["shutil-resolved"]])
+                .env("PATH", paths)
+                .cache_binary_paths(true)
+        };
+        let command = pipeline().command("shutil-resolved");
+        assert_eq!(command.get_program(), binary.as_os_str());
+        assert_eq!(pipeline().output().unwrap(), "found\n");
+
+        // Without the opt-in, the name is left for the OS to search for.
+        let command = Pipeline::new(vec![vec!["shutil-resolved"]]).command("shutil-resolved");
+        assert_eq!(command.get_program(), "shutil-resolved");
+
+        // A cached path that has gone away is forgotten once it fails.
+        std::fs::remove_file(&binary).unwrap();
+        assert_eq!(pipeline().output().unwrap_err().kind(), ErrorKind::OsError);
+        let key = ("shutil-resolved".to_string(), OsString::from(paths));
+        assert!(!lock(binary_cache()).contains_key(&key));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_command_keeps_argv0() {
+        let output = Pipeline::new(vec![vec!["sh", "-c", "cat /proc/$$/cmdline"]])
+            .cache_binary_paths(true)
+            .output();
+        assert!(output.unwrap().starts_with("sh\0-c\0"));
+    }
+
+    #[test]
+    fn test_binary_cache() {
+        let dir = env::temp_dir().join(format!("shutil-cache-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let binary = dir.join("shutil-cached");
+        std::fs::write(&binary, "").unwrap();
+        let paths = OsString::from(&dir);
+
+        // A file without execute permission is not a binary.
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            assert_eq!(find_in("shutil-cached", paths.clone()), None);
+            let executable = std::fs::Permissions::from_mode(0o755);
+            std::fs::set_permissions(&binary, executable).unwrap();
+        }
+
+        assert_eq!(
+            find_in("shutil-cached", paths.clone()),
+            Some(binary.clone())
+        );
+
+        // The removed binary is still found until the cache is cleared.
+        std::fs::remove_file(&binary).unwrap();
+        assert_eq!(
+            find_in("shutil-cached", paths.clone()),
+            Some(binary.clone())
+        );
+        assert_eq!(
+            find_in("shutil-cached", OsString::from("/nonexistent")),
+            None
+        );
+
+        clear_binary_cache();
+        assert_eq!(find_in("shutil-cached", paths), None);
+        std::fs::remove_dir(&dir).unwrap();
+    }
+
+    // A writer whose contents can be inspected after it is moved.
+    #[derive(Clone, Default)]
+    struct SharedBuf(std::sync::Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_io_default() {
+        let result = Pipeline::new(vec![vec!["echo", "foo"], vec!["rev"]])
+            .io(IoConfig::default())
+            .exec()
+            .unwrap();
+        assert_eq!(result.code, Some(0));
+        assert_eq!(result.stdout, "oof\n");
+        assert_eq!(result.stderr, "");
+    }
+
+    #[test]
+    fn test_io_reader_capture() {
+        let result = Pipeline::new(vec![vec!["sh", "-c", "rev; echo err >&2; exit 2"]])
+            .io(IoConfig {
+                stdin: StdinMode::Reader(Box::new(io::Cursor::new("foo\n"))),
+                stdout: StdoutMode::Capture,
+                stderr: StderrMode::Capture,
+            })
+            .exec()
+            .unwrap();
+        assert_eq!(result.code, Some(2));
+        assert_eq!(result.stdout, "oof\n");
+        assert_eq!(result.stderr, "err\n");
+    }
+
+    #[test]
+    fn test_io_files() {
+        let dir = env::temp_dir().join(format!("shutil-io-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("in"), "foo\n").unwrap();
+
+        let result = Pipeline::new(vec![
+            vec!["sh", "-c", "echo first >&2; cat"],
+            vec!["sh", "-c", "sleep 0.1; echo second >&2; rev"],
+        ])
+        .io(IoConfig {
+            stdin: StdinMode::File(dir.join("in")),
+            stdout: StdoutMode::File(dir.join("out")),
+            stderr: StderrMode::File(dir.join("err")),
+        })
+        .exec()
+        .unwrap();
+        assert_eq!(result.code, Some(0));
+        assert_eq!(result.stdout, "");
+        assert_eq!(std::fs::read_to_string(dir.join("out")).unwrap(), "oof\n");
+        assert_eq!(
+            std::fs::read_to_string(dir.join("err")).unwrap(),
+            "first\nsecond\n"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_io_missing_stdin_file() {
+        let result = Pipeline::new(vec![vec!["cat"]])
+            .io(IoConfig {
+                stdin: StdinMode::File(PathBuf::from("/does/not/exist")),
+                ..IoConfig::default()
+            })
+            .exec();
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::OsError);
+    }
+
+    #[test]
+    fn test_io_null_writer() {
+        let buf = SharedBuf::default();
+        let result = Pipeline::new(vec![vec!["sh", "-c", "cat; echo done; echo err >&2"]])
+            .io(IoConfig {
+                stdin: StdinMode::Null,
+                stdout: StdoutMode::Writer(Box::new(buf.clone())),
+                stderr: StderrMode::Null,
+            })
+            .exec()
+            .unwrap();
+        assert_eq!(result.code, Some(0));
+        assert_eq!(result.stdout, "");
+        assert_eq!(result.stderr, "");
+        assert_eq!(*buf.0.lock().unwrap(), b"done\n");
+    }
+
+    #[test]
+    fn test_io_writer_needs_exec() {
+        let buf = SharedBuf::default();
+        let pipeline = || {
+            Pipeline::new(vec![vec!["echo", "foo"]]).io(IoConfig {
+                stdout: StdoutMode::Writer(Box::new(buf.clone())),
+                ..IoConfig::default()
+            })
+        };
+        assert_eq!(
+            pipeline().output().unwrap_err().kind(),
+            ErrorKind::InvalidFormatError
+        );
+        assert_eq!(
+            pipeline().outcome().unwrap_err().kind(),
+            ErrorKind::InvalidFormatError
+        );
+        assert!(buf.0.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_io_writer_deadline() {
+        let start = Instant::now();
+        let buf = SharedBuf::default();
+        let result = Pipeline::new(vec![vec!["sh", "-c", "echo first; sleep 3"]])
+            .io(IoConfig {
+                stdout: StdoutMode::Writer(Box::new(buf.clone())),
+                ..IoConfig::default()
+            })
+            .deadline(Instant::now() + Duration::from_millis(200))
+            .exec();
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::TimeoutError);
+        assert_eq!(*buf.0.lock().unwrap(), b"first\n");
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_io_null_inherit() {
+        let result = Pipeline::new(vec![vec!["echo", "foo"]])
+            .io(IoConfig {
+                stdin: StdinMode::Inherit,
+                stdout: StdoutMode::Null,
+                stderr: StderrMode::Inherit,
+            })
+            .exec()
+            .unwrap();
+        assert_eq!(result.code, Some(0));
+        assert_eq!(result.stdout, "");
+
+        // Inherited stdout is not captured.
+        let output = Pipeline::new(vec![vec!["true"]])
+            .io(IoConfig {
+                stdout: StdoutMode::Inherit,
+                ..IoConfig::default()
+            })
+            .output();
+        assert_eq!(output.unwrap(), "");
+    }
+
+    // Held by tests that signal the test process or check its handlers.
+    #[cfg(unix)]
+    static SIGNAL_TEST_LOCK: Mutex<()> = Mutex::new(());
+
+    #[cfg(unix)]
+    #[test]
+    fn test_handle_ctrl_c() {
+        let _lock = lock(&SIGNAL_TEST_LOCK);
+        let start = Instant::now();
+        let signaller = thread::spawn(|| {
+            thread::sleep(Duration::from_millis(200));
+            unsafe {
+                libc::kill(libc::getpid(), libc::SIGINT);
+            }
+        });
+        let output = Pipeline::new(vec![vec!["sleep", "10"]])
+            .handle_ctrl_c(true)
+            .output();
+        signaller.join().unwrap();
+        assert_eq!(output.unwrap_err().kind(), ErrorKind::Cancelled);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_forward_signals() {
+        let _lock = lock(&SIGNAL_TEST_LOCK);
+        let signaller = thread::spawn(|| {
+            thread::sleep(Duration::from_millis(300));
+            unsafe {
+                libc::kill(libc::getpid(), libc::SIGTERM);
+            }
+        });
+        // Every stage is in the group that receives the signal, so the second
+        // ignores it to keep passing on the output.
+        let output = Pipeline::new(vec![
+            vec!["sh", "-c", "trap 'echo term; exit 0' TERM; sleep 10 & wait"],
+            vec!["sh", "-c", "trap '' TERM; cat"],
+        ])
+        .forward_signals(&[libc::SIGTERM])
+        .output();
+        signaller.join().unwrap();
+        assert_eq!(output.unwrap(), "term\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_forward_signals_with_ctrl_c() {
+        let _lock = lock(&SIGNAL_TEST_LOCK);
+        let signaller = thread::spawn(|| {
+            thread::sleep(Duration::from_millis(200));
+            unsafe {
+                libc::kill(libc::getpid(), libc::SIGINT);
+            }
+        });
+        let output = Pipeline::new(vec![vec!["sleep", "10"]])
+            .handle_ctrl_c(true)
+            .forward_signals(&[libc::SIGINT])
+            .output();
+        signaller.join().unwrap();
+        assert_eq!(output.unwrap_err().kind(), ErrorKind::Cancelled);
+
+        // Both handlers are uninstalled, leaving the default disposition.
+        let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+        unsafe {
+            libc::sigaction(libc::SIGINT, std::ptr::null(), &mut action);
+        }
+        assert_eq!(action.sa_sigaction, libc::SIG_DFL);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_forward_signals_invalid() {
+        let output = Pipeline::new(vec![vec!["true"]])
+            .forward_signals(&[0])
+            .output();
+        assert_eq!(output.unwrap_err().kind(), ErrorKind::InvalidFormatError);
+        let output = Pipeline::new(vec![vec!["true"]])
+            .forward_signals(&[libc::SIGKILL])
+            .output();
+        assert_eq!(output.unwrap_err().kind(), ErrorKind::OsError);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    #[ignore = "requires permission to create cgroups"]
+    fn test_cgroup() {
+        let cgroup = PathBuf::from(format!("/sys/fs/cgroup/shutil-test-{}", std::process::id()));
+        std::fs::create_dir(&cgroup).unwrap();
+        let output = Pipeline::new(vec![vec!["cat", "/proc/self/cgroup"]])
+            .cgroup(&cgroup)
+            .output();
+        let script = format!("echo $$; cat {}/cgroup.procs", cgroup.display());
+        let procs = Pipeline::new(vec![vec!["sh", "-c", &script]])
+            .cgroup(&cgroup)
+            .output();
+        std::fs::remove_dir(&cgroup).unwrap();
+
+        let name = cgroup.file_name().unwrap().to_str().unwrap();
+        assert!(output.unwrap().trim_end().ends_with(name));
+        let procs = procs.unwrap();
+        let mut lines = procs.lines();
+        let pid = lines.next().unwrap();
+        assert!(lines.any(|line| line == pid));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_cgroup_missing() {
+        let output = Pipeline::new(vec![vec!["true"]])
+            .cgroup(Path::new("/does/not/exist"))
+            .output();
+        assert_eq!(output.unwrap_err().kind(), ErrorKind::OsError);
+    }
+
+    #[test]
+    fn test_outcome() {
+        let outcome = Pipeline::new(vec![
+            vec!["sh", "-c", "echo foo; echo err >&2; sleep 0.1"],
+            vec!["rev"],
+        ])
+        .outcome()
+        .unwrap();
+        assert_eq!(outcome.stdout, "oof\n");
+        assert_eq!(outcome.stderr, "err\n");
+        assert_eq!(outcome.code, 0);
+        assert!(outcome.duration >= Duration::from_millis(100));
+        assert_eq!(
+            outcome.command,
+            "sh -c 'echo foo; echo err >&2; sleep 0.1' | rev"
+        );
+
+        let outcome = Pipeline::new(vec![vec!["sh", "-c", "exit 3"]])
+            .outcome()
+            .unwrap();
+        assert_eq!(outcome.code, 3);
+    }
+
+    #[test]
+    fn test_shell_escape() {
+        assert_eq!(shell_escape("ls"), "ls");
+        assert_eq!(shell_escape("--color=auto"), "--color=auto");
+        assert_eq!(shell_escape(""), "''");
+        assert_eq!(shell_escape("a b"), "'a b'");
+        assert_eq!(shell_escape("it's"), "'it'\\''s'");
+        assert_eq!(shell_escape("$HOME"), "'$HOME'");
+    }
+
+    #[test]
+    fn test_max_stages() {
+        let commands = || vec![vec!["echo", "foo"], vec!["rev"], vec!["cat"]];
+        assert_eq!(
+            Pipeline::new(commands()).max_stages(3).output().unwrap(),
+            "oof\n"
+        );
+
+        let err = Pipeline::new(commands())
+            .max_stages(2)
+            .output()
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidFormatError);
+        assert!(err.to_string().contains("3 stages exceed the limit of 2"));
+    }
+
+    #[test]
+    fn test_max_args() {
+        let commands = || vec![vec!["echo", "a", "b"], vec!["cat"]];
+        assert_eq!(
+            Pipeline::new(commands()).max_args(2).output().unwrap(),
+            "a b\n"
+        );
+
+        let err = Pipeline::new(commands()).max_args(1).output().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidFormatError);
+        assert!(err
+            .to_string()
+            .contains("stage 0: 2 arguments exceed the limit of 1"));
+    }
+
+    #[test]
+    fn test_optional_tail() {
+        let commands = || {
+            vec![
+                vec!["echo", "foo"],
+                vec!["rev"],
+                vec!["shutil-no-such-formatter"],
+            ]
+        };
+        let output = Pipeline::new(commands()).optional_tail(2).output();
+        assert_eq!(output.unwrap(), "oof\n");
+
+        let output = Pipeline::new(commands()).output();
+        assert_eq!(output.unwrap_err().kind(), ErrorKind::OsError);
+
+        // Stages before the optional tail are still required.
+        let output = Pipeline::new(vec![vec!["echo", "foo"], vec!["shutil-no-such-formatter"]])
+            .optional_tail(2)
+            .output();
+        assert!(output.is_err());
+
+        // An earlier captured stage is still returned.
+        let output = Pipeline::new(commands())
+            .capture_stage(0)
+            .optional_tail(1)
+            .output();
+        assert_eq!(output.unwrap(), "foo\n");
+    }
+
+    #[test]
+    fn test_read_timeout() {
+        let start = Instant::now();
+        let output = Pipeline::new(vec![vec![
+            "sh",
+            "-c",
+            "for i in 1 2 3; do echo $i; sleep 0.1; done; sleep 5; echo done",
+        ]])
+        .read_timeout(Duration::from_millis(500))
+        .output();
+        let err = output.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimeoutError);
+        assert_eq!(err.partial_output(), Some("1\n2\n3\n"));
+        assert!(start.elapsed() < Duration::from_secs(3));
+    }
+
+    #[test]
+    fn test_read_timeout_met() {
+        // The total runtime exceeds the read timeout, but no read stalls.
+        let output = Pipeline::new(vec![vec![
+            "sh",
+            "-c",
+            "for i in 1 2 3 4 5; do echo $i; sleep 0.1; done",
+        ]])
+        .read_timeout(Duration::from_millis(300))
+        .output();
+        assert_eq!(output.unwrap(), "1\n2\n3\n4\n5\n");
+    }
+
+    #[test]
+    fn test_read_timeout_writers() {
+        let stalling = || {
+            Pipeline::new(vec![vec!["sh", "-c", "echo first; sleep 3"]])
+                .read_timeout(Duration::from_millis(200))
+        };
+        let start = Instant::now();
+
+        let mut out = Vec::new();
+        let result = stalling().to_writer(&mut out);
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::TimeoutError);
+        assert_eq!(out, b"first\n");
+
+        let mut out = Vec::new();
+        let result = stalling().to_writer_timeout(&mut out, Duration::from_secs(10));
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::TimeoutError);
+        assert_eq!(out, b"first\n");
+
+        let buf = SharedBuf::default();
+        let result = stalling()
+            .io(IoConfig {
+                stdout: StdoutMode::Writer(Box::new(buf.clone())),
+                ..IoConfig::default()
+            })
+            .exec();
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::TimeoutError);
+        assert_eq!(*buf.0.lock().unwrap(), b"first\n");
+
+        assert!(start.elapsed() < Duration::from_secs(3));
+    }
+
+    #[test]
+    fn test_stage_io() {
+        let dir = env::temp_dir().join(format!("shutil-stage-io-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let buf = SharedBuf::default();
+
+        let result = Pipeline::new(vec![
+            vec!["sh", "-c", "cat; echo out; echo err0 >&2"],
+            vec!["sh", "-c", "rev; echo err1 >&2"],
+        ])
+        .stage_io(
+            0,
+            StdinMode::Null,
+            StdoutMode::Capture,
+            StderrMode::File(dir.join("err0")),
+        )
+        .stage_io(
+            1,
+            StdinMode::Pipe,
+            StdoutMode::Writer(Box::new(buf.clone())),
+            StderrMode::Capture,
+        )
+        .exec()
+        .unwrap();
+        assert_eq!(result.code, Some(0));
+        assert_eq!(result.stdout, "");
+        assert_eq!(result.stderr, "err1\n");
+        assert_eq!(*buf.0.lock().unwrap(), b"tuo\n");
+        assert_eq!(std::fs::read_to_string(dir.join("err0")).unwrap(), "err0\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_stage_io_stdout_elsewhere() {
+        // With the first stage's stdout discarded, the second reads nothing.
+        let output = Pipeline::new(vec![vec!["echo", "foo"], vec!["wc", "-c"]])
+            .stage_io(0, StdinMode::Pipe, StdoutMode::Null, StderrMode::Inherit)
+            .output();
+        assert_eq!(output.unwrap().trim(), "0");
+    }
+
+    #[test]
+    fn test_stage_io_out_of_range() {
+        let output = Pipeline::new(vec![vec!["echo", "foo"]])
+            .stage_io(1, StdinMode::Pipe, StdoutMode::Capture, StderrMode::Inherit)
+            .output();
+        assert_eq!(
+            output.as_ref().unwrap_err().kind(),
+            ErrorKind::InvalidFormatError
+        );
+    }
+
+    #[test]
+    fn test_transform_streaming() {
+        // Far more output than the pipe buffers, so the transform has to keep
+        // up with the stream rather than collect it.
+        let output = Pipeline::new(vec![
+            vec!["yes", "abc"],
+            vec!["head", "-n", "200000"],
+            vec!["cat"],
+        ])
+        .transform_streaming(1, |reader, writer| {
+            let mut line = Vec::new();
+            while reader.read_until(b'\n', &mut line)? > 0 {
+                line.make_ascii_uppercase();
+                writer.write_all(&line)?;
+                line.clear();
+            }
+            Ok(())
+        })
+        .output()
+        .unwrap();
+        assert_eq!(output.len(), 200000 * 4);
+        assert!(output.lines().all(|line| line == "ABC"));
+    }
+
+    #[test]
+    fn test_transform_streaming_next_stage_exits() {
+        // The transform's writes fail once head exits, which is not an error.
+        let output = Pipeline::new(vec![vec!["yes"], vec!["head", "-n", "2"]])
+            .transform_streaming(0, |reader, writer| io::copy(reader, writer).map(|_| ()))
+            .output();
+        assert_eq!(output.unwrap(), "y\ny\n");
+    }
+
+    #[test]
+    fn test_transform_streaming_error() {
+        let output = Pipeline::new(vec![vec!["echo", "foo"], vec!["cat"]])
+            .transform_streaming(0, |_reader, _writer| Err(io::Error::other("bad input")))
+            .output();
+        assert_eq!(output.unwrap_err().kind(), ErrorKind::UnknownError);
+
+        let output = Pipeline::new(vec![vec!["echo", "foo"]])
+            .transform_streaming(0, |_reader, _writer| Ok(()))
+            .output();
+        assert_eq!(output.unwrap_err().kind(), ErrorKind::InvalidFormatError);
+    }
+
+    #[test]
+    fn test_transform_streaming_captured_stage() {
+        // The capture holds stage 0's own output, while grep only succeeds
+        // if it is fed the transformed output.
+        let output = Pipeline::new(vec![vec!["echo", "foo"], vec!["grep", "-q", "FOO"]])
+            .capture_stage(0)
+            .transform_streaming(0, |reader, writer| {
+                let mut buf = Vec::new();
+                reader.read_to_end(&mut buf)?;
+                writer.write_all(&buf.to_ascii_uppercase())
+            })
+            .output();
+        assert_eq!(output.unwrap(), "foo\n");
+    }
+
+    #[test]
+    fn test_capture_stage_out_of_range() {
+        let output = Pipeline::new(vec![vec!["echo", "foo"]])
+            .capture_stage(1)
+            .output();
+        assert_eq!(
+            output.as_ref().unwrap_err().kind(),
+            ErrorKind::InvalidFormatError
+        );
+    }
+}